@@ -0,0 +1,335 @@
+use std::fmt::{self, Display};
+
+use crate::{
+    chunk::LineStart,
+    object::{Obj, ObjFunction, ObjType, as_obj},
+    value::Value,
+    verifier::{self, VerifyError},
+    vm::VM,
+};
+
+pub const MAGIC: &[u8; 4] = b"LOXC";
+/// Must be bumped whenever the layout or the instruction set changes, older files are rejected
+pub const FORMAT_VERSION: u16 = 4;
+
+const TAG_NIL: u8 = 0;
+const TAG_BOOL: u8 = 1;
+const TAG_NUMBER: u8 = 2;
+const TAG_STRING: u8 = 3;
+const TAG_FUNCTION: u8 = 4;
+
+/// Most functions nested in each other's constants, the loader recurses into each one
+const MAX_FUNCTION_DEPTH: usize = 256;
+
+#[derive(Debug)]
+pub enum LoadError {
+    NotBytecode,
+    IncompatibleVersion { found: u16 },
+    UnexpectedEnd,
+    InvalidString,
+    InvalidConstant(u8),
+    TooDeeplyNested,
+    Invalid(VerifyError),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::NotBytecode => write!(f, "Not a compiled Lox file."),
+            LoadError::IncompatibleVersion { found } => write!(
+                f,
+                "Incompatible bytecode version {found}, this interpreter only runs version {FORMAT_VERSION}. Recompile the script."
+            ),
+            LoadError::UnexpectedEnd => write!(f, "Unexpected end of bytecode file."),
+            LoadError::InvalidString => write!(f, "Invalid UTF-8 string constant."),
+            LoadError::InvalidConstant(tag) => write!(f, "Invalid constant type {tag}."),
+            LoadError::TooDeeplyNested => write!(
+                f,
+                "Functions are nested more than {MAX_FUNCTION_DEPTH} levels deep."
+            ),
+            LoadError::Invalid(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Encodes a compiled script as a `.loxc` file, so it can be run without parsing it again.
+///
+/// Layout, every integer is little-endian:
+/// ```text
+/// file      := MAGIC version:u16 source:string function
+/// function  := name arity:u32 upvalue_count:u32 code lines spans constants
+/// name      := 0:u8 | 1:u8 string          (the top-level script has no name)
+/// code      := len:u32 byte*
+/// lines     := len:u32 line*
+/// line      := offset:u32 line:u32         (the first byte of each run from the same line)
+/// spans     := len:u32 byte*               (the delta encoded table of `Chunk::spans`)
+/// constants := len:u32 constant*
+/// constant  := NIL | BOOL b:u8 | NUMBER f64 | STRING string | FUNCTION function
+/// string    := len:u32 utf8*
+/// ```
+///
+/// `source` is the name of the script's source file, for error messages
+pub fn serialize(source: &str, function: &ObjFunction) -> Vec<u8> {
+    let mut writer = Writer { bytes: Vec::new() };
+    writer.bytes.extend_from_slice(MAGIC);
+    writer.u16(FORMAT_VERSION);
+    writer.string(source);
+    writer.function(function);
+    writer.bytes
+}
+
+/// Allocates the script function and everything it references in the VM, and returns it with its source file name.
+/// The code of every function is verified, because the VM trusts it to be well-formed like the compiler's output.
+pub fn deserialize(bytes: &[u8], vm: &mut VM) -> Result<(String, *mut ObjFunction), LoadError> {
+    if !is_bytecode(bytes) {
+        return Err(LoadError::NotBytecode);
+    }
+
+    let mut reader = Reader {
+        bytes,
+        offset: MAGIC.len(),
+        depth: 0,
+        vm,
+    };
+    let version = reader.u16()?;
+    if version != FORMAT_VERSION {
+        return Err(LoadError::IncompatibleVersion { found: version });
+    }
+
+    let source = reader.string()?.to_owned();
+    let function = reader.function()?;
+    // SAFETY: The function has just been loaded, and nothing is allocated while it is verified
+    verifier::verify_script(unsafe { &*function }).map_err(LoadError::Invalid)?;
+    Ok((source, function))
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: usize) {
+        let value = u32::try_from(value).expect("lengths to fit in 32 bits");
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, chars: &str) {
+        self.u32(chars.len());
+        self.bytes.extend_from_slice(chars.as_bytes());
+    }
+
+    fn function(&mut self, function: &ObjFunction) {
+        if function.name.is_null() {
+            self.u8(0);
+        } else {
+            self.u8(1);
+            self.string(function.name());
+        }
+        self.u32(function.arity);
+        self.u32(function.upvalue_count);
+
+        let chunk = &function.chunk;
+        self.u32(chunk.code.len());
+        self.bytes.extend_from_slice(&chunk.code);
+
+        self.u32(chunk.lines.len());
+        for start in &chunk.lines {
+            self.u32(start.offset);
+            self.u32(start.line);
+        }
+
+        self.u32(chunk.spans.len());
+        self.bytes.extend_from_slice(&chunk.spans);
+
+        self.u32(chunk.constants.len());
+        for &constant in &chunk.constants {
+            self.constant(constant);
+        }
+    }
+
+    fn constant(&mut self, constant: Value) {
+        match constant {
+            Value::Nil => self.u8(TAG_NIL),
+            Value::Bool(b) => {
+                self.u8(TAG_BOOL);
+                self.u8(b.into());
+            }
+            Value::Number(n) => {
+                self.u8(TAG_NUMBER);
+                self.bytes.extend_from_slice(&n.to_le_bytes());
+            }
+            // SAFETY: Constants only ever hold pointers to live objects
+            Value::Obj(obj) => match unsafe { (*obj).typ } {
+                ObjType::String => {
+                    self.u8(TAG_STRING);
+                    self.string(&constant.as_string().expect("to be a string").chars);
+                }
+                ObjType::Function => {
+                    self.u8(TAG_FUNCTION);
+                    // SAFETY: We have checked the type of the object
+                    self.function(unsafe { as_obj(obj) });
+                }
+                typ => unreachable!("the compiler doesn't emit {typ:?} constants"),
+            },
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    /// How many functions are being loaded, each one is nested in the previous one's constants
+    depth: usize,
+    vm: &'a mut VM,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], LoadError> {
+        let end = self
+            .offset
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or(LoadError::UnexpectedEnd)?;
+        let bytes = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, LoadError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<usize, LoadError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn f64(&mut self) -> Result<f64, LoadError> {
+        let bytes = self.take(8)?;
+        Ok(f64::from_le_bytes(
+            bytes.try_into().expect("to take 8 bytes"),
+        ))
+    }
+
+    fn string(&mut self) -> Result<&str, LoadError> {
+        let len = self.u32()?;
+        std::str::from_utf8(self.take(len)?).map_err(|_| LoadError::InvalidString)
+    }
+
+    fn function(&mut self) -> Result<*mut ObjFunction, LoadError> {
+        if self.depth == MAX_FUNCTION_DEPTH {
+            return Err(LoadError::TooDeeplyNested);
+        }
+
+        let function = self.vm.new_function();
+        // The function is only reachable from the loader until it is finished, like while compiling it
+        self.vm.compiler_roots.push(function);
+        self.depth += 1;
+        let result = self.function_body(function);
+        self.depth -= 1;
+        self.vm.compiler_roots.pop();
+
+        result.map(|()| function)
+    }
+
+    fn function_body(&mut self, function: *mut ObjFunction) -> Result<(), LoadError> {
+        // SAFETY: The function has just been allocated, and it is rooted while it is being loaded.
+        // Every access goes through the pointer, because loading constants can allocate.
+        unsafe {
+            if self.u8()? == 1 {
+                let name = self.string()?.to_owned();
+                (*function).name = self.vm.take_string(name);
+            }
+            (*function).arity = self.u32()?;
+            (*function).upvalue_count = self.u32()?;
+
+            let code_len = self.u32()?;
+            (*function).chunk.code = self.take(code_len)?.to_vec();
+
+            let lines_len = self.u32()?;
+            let mut lines = Vec::with_capacity(lines_len.min(self.bytes.len()));
+            for _ in 0..lines_len {
+                lines.push(LineStart {
+                    offset: self.u32()?,
+                    line: self.u32()?,
+                });
+            }
+            (*function).chunk.lines = lines;
+
+            let spans_len = self.u32()?;
+            (*function).chunk.spans = self.take(spans_len)?.to_vec();
+
+            let constants_len = self.u32()?;
+            for _ in 0..constants_len {
+                let constant = self.constant()?;
+                (*function).chunk.constants.push(constant);
+            }
+
+            // The nested functions in the constants have been verified while they were loaded
+            (*function).max_stack = verifier::verify(&*function).map_err(LoadError::Invalid)?;
+        }
+
+        Ok(())
+    }
+
+    fn constant(&mut self) -> Result<Value, LoadError> {
+        let constant = match self.u8()? {
+            TAG_NIL => Value::Nil,
+            TAG_BOOL => Value::Bool(self.u8()? != 0),
+            TAG_NUMBER => Value::Number(self.f64()?),
+            TAG_STRING => {
+                let chars = self.string()?.to_owned();
+                Value::Obj(self.vm.take_string(chars) as *mut Obj)
+            }
+            TAG_FUNCTION => Value::Obj(self.function()? as *mut Obj),
+            tag => return Err(LoadError::InvalidConstant(tag)),
+        };
+
+        Ok(constant)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_deeply_nested_functions() {
+        let mut writer = Writer { bytes: Vec::new() };
+        writer.bytes.extend_from_slice(MAGIC);
+        writer.u16(FORMAT_VERSION);
+        writer.string("test.lox");
+        for _ in 0..=MAX_FUNCTION_DEPTH {
+            // An unnamed function without code, whose only constant is the next function.
+            // The integers are its arity, upvalue count, and the lengths of its code, lines, spans and constants.
+            writer.u8(0);
+            for len in [0, 0, 0, 0, 0, 1] {
+                writer.u32(len);
+            }
+            writer.u8(TAG_FUNCTION);
+        }
+
+        let mut vm = VM::new();
+        assert!(matches!(
+            deserialize(&writer.bytes, &mut vm),
+            Err(LoadError::TooDeeplyNested)
+        ));
+    }
+}
@@ -1,6 +1,7 @@
-use serde::{Deserialize, Serialize};
-
-use crate::value::Value;
+use crate::{
+    scanner::{Location, Span},
+    value::Value,
+};
 
 #[allow(non_snake_case)]
 pub mod OP {
@@ -12,6 +13,47 @@ pub mod OP {
     pub const SUBTRACT: u8 = 5;
     pub const MULTIPLY: u8 = 6;
     pub const DIVIDE: u8 = 7;
+    pub const NIL: u8 = 8;
+    pub const TRUE: u8 = 9;
+    pub const FALSE: u8 = 10;
+    pub const NOT: u8 = 11;
+    pub const EQUAL: u8 = 12;
+    pub const GREATER: u8 = 13;
+    pub const LESS: u8 = 14;
+    pub const PRINT: u8 = 15;
+    pub const POP: u8 = 16;
+    pub const DEFINE_GLOBAL: u8 = 17;
+    pub const DEFINE_GLOBAL_LONG: u8 = 18;
+    pub const GET_GLOBAL: u8 = 19;
+    pub const GET_GLOBAL_LONG: u8 = 20;
+    pub const SET_GLOBAL: u8 = 21;
+    pub const SET_GLOBAL_LONG: u8 = 22;
+    pub const GET_LOCAL: u8 = 23;
+    pub const SET_LOCAL: u8 = 24;
+    pub const JUMP: u8 = 25;
+    pub const JUMP_IF_FALSE: u8 = 26;
+    pub const LOOP: u8 = 27;
+    pub const CALL: u8 = 28;
+    pub const CLOSURE: u8 = 29;
+    pub const CLOSURE_LONG: u8 = 30;
+    pub const GET_UPVALUE: u8 = 31;
+    pub const SET_UPVALUE: u8 = 32;
+    pub const CLOSE_UPVALUE: u8 = 33;
+    pub const CLASS: u8 = 34;
+    pub const CLASS_LONG: u8 = 35;
+    pub const GET_PROPERTY: u8 = 36;
+    pub const GET_PROPERTY_LONG: u8 = 37;
+    pub const SET_PROPERTY: u8 = 38;
+    pub const SET_PROPERTY_LONG: u8 = 39;
+    pub const METHOD: u8 = 40;
+    pub const METHOD_LONG: u8 = 41;
+    pub const INHERIT: u8 = 42;
+    pub const GET_SUPER: u8 = 43;
+    pub const GET_SUPER_LONG: u8 = 44;
+    pub const INVOKE: u8 = 45;
+    pub const INVOKE_LONG: u8 = 46;
+    pub const SUPER_INVOKE: u8 = 47;
+    pub const SUPER_INVOKE_LONG: u8 = 48;
 }
 
 #[repr(u8)]
@@ -25,6 +67,47 @@ pub enum OpCode {
     Subtract = OP::SUBTRACT,
     Multiply = OP::MULTIPLY,
     Divide = OP::DIVIDE,
+    Nil = OP::NIL,
+    True = OP::TRUE,
+    False = OP::FALSE,
+    Not = OP::NOT,
+    Equal = OP::EQUAL,
+    Greater = OP::GREATER,
+    Less = OP::LESS,
+    Print = OP::PRINT,
+    Pop = OP::POP,
+    DefineGlobal = OP::DEFINE_GLOBAL,
+    DefineGlobalLong = OP::DEFINE_GLOBAL_LONG,
+    GetGlobal = OP::GET_GLOBAL,
+    GetGlobalLong = OP::GET_GLOBAL_LONG,
+    SetGlobal = OP::SET_GLOBAL,
+    SetGlobalLong = OP::SET_GLOBAL_LONG,
+    GetLocal = OP::GET_LOCAL,
+    SetLocal = OP::SET_LOCAL,
+    Jump = OP::JUMP,
+    JumpIfFalse = OP::JUMP_IF_FALSE,
+    Loop = OP::LOOP,
+    Call = OP::CALL,
+    Closure = OP::CLOSURE,
+    ClosureLong = OP::CLOSURE_LONG,
+    GetUpvalue = OP::GET_UPVALUE,
+    SetUpvalue = OP::SET_UPVALUE,
+    CloseUpvalue = OP::CLOSE_UPVALUE,
+    Class = OP::CLASS,
+    ClassLong = OP::CLASS_LONG,
+    GetProperty = OP::GET_PROPERTY,
+    GetPropertyLong = OP::GET_PROPERTY_LONG,
+    SetProperty = OP::SET_PROPERTY,
+    SetPropertyLong = OP::SET_PROPERTY_LONG,
+    Method = OP::METHOD,
+    MethodLong = OP::METHOD_LONG,
+    Inherit = OP::INHERIT,
+    GetSuper = OP::GET_SUPER,
+    GetSuperLong = OP::GET_SUPER_LONG,
+    Invoke = OP::INVOKE,
+    InvokeLong = OP::INVOKE_LONG,
+    SuperInvoke = OP::SUPER_INVOKE,
+    SuperInvokeLong = OP::SUPER_INVOKE_LONG,
     // NOTE: Don't forget to update try_from's implementation
 }
 
@@ -39,7 +122,7 @@ impl TryFrom<u8> for OpCode {
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         const MIN_OPCODE: OpCode = OpCode::Return;
-        const MAX_OPCODE: OpCode = OpCode::Divide;
+        const MAX_OPCODE: OpCode = OpCode::SuperInvokeLong;
 
         match value {
             x if x >= MIN_OPCODE as u8 && x <= MAX_OPCODE as u8 => {
@@ -50,10 +133,33 @@ impl TryFrom<u8> for OpCode {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+/// The first instruction byte of a run of bytes that all come from the same line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineStart {
+    pub offset: usize,
+    pub line: usize,
+}
+
+/// The first instruction byte of a run of bytes that all come from the same token
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SpanStart {
+    pub offset: usize,
+    pub column: usize,
+    pub span: Span,
+}
+
 pub struct Chunk {
     pub code: Vec<u8>,
-    pub lines: Vec<usize>,
+    /// Run-length encoded source lines of the code, sorted by offset, the first one starts at offset 0
+    pub lines: Vec<LineStart>,
+    /// Columns and spans of the code, which are only needed for error messages.
+    /// A run starts at almost every token, so each `SpanStart` is delta encoded relative to the previous one:
+    /// ```text
+    /// span_start := offset_delta:varint column:varint start_delta:zigzag_varint len:varint
+    /// ```
+    pub spans: Vec<u8>,
+    /// The last run written to `spans`, which the next one is encoded relative to
+    last_span: Option<SpanStart>,
     pub constants: Vec<Value>,
 }
 
@@ -62,41 +168,199 @@ impl Chunk {
         Chunk {
             code: Vec::new(),
             lines: Vec::new(),
+            spans: Vec::new(),
+            last_span: None,
             constants: Vec::new(),
         }
     }
 
-    pub fn write(&mut self, byte: u8, line: usize) {
-        // Both arrays must be in sync, they must push to the same index
+    pub fn write(&mut self, byte: u8, location: Location) {
+        // Consecutive bytes usually come from the same line or token, so only the start of each run is stored
+        if self
+            .lines
+            .last()
+            .is_none_or(|start| start.line != location.line)
+        {
+            self.lines.push(LineStart {
+                offset: self.code.len(),
+                line: location.line,
+            });
+        }
+
+        let start = SpanStart {
+            offset: self.code.len(),
+            column: location.column,
+            span: location.span,
+        };
+        if self
+            .last_span
+            .is_none_or(|last| (last.column, last.span) != (start.column, start.span))
+        {
+            let previous = self.last_span.unwrap_or_default();
+            write_varint(&mut self.spans, start.offset - previous.offset);
+            write_varint(&mut self.spans, start.column);
+            write_varint(
+                &mut self.spans,
+                zigzag(start.span.start as i64 - previous.span.start as i64),
+            );
+            write_varint(&mut self.spans, start.span.end - start.span.start);
+            self.last_span = Some(start);
+        }
+
         self.code.push(byte);
-        self.lines.push(line); // TODO: Implement run-length encoding for lines
     }
 
-    pub fn write_constant(&mut self, value: Value, line: usize) {
-        if self.constants.len() <= u8::MAX as usize {
-            self.constants.push(value);
-            self.write(OpCode::Constant.into(), line);
-            self.write((self.constants.len() - 1) as u8, line);
+    /// Returns the source line of the byte at `offset`
+    pub fn get_line(&self, offset: usize) -> usize {
+        let run = self.lines.partition_point(|start| start.offset <= offset);
+        self.lines[run - 1].line
+    }
 
-            return;
+    /// Returns the source location of the byte at `offset`, decoding the spans is slow, it is only meant for errors
+    pub fn get_location(&self, offset: usize) -> Location {
+        let spans = decode_spans(&self.spans).expect("the span table to be valid");
+        let run = spans.partition_point(|start| start.offset <= offset);
+        let start = spans[run - 1];
+
+        Location {
+            line: self.get_line(offset),
+            column: start.column,
+            span: start.span,
         }
+    }
+
+    pub fn write_constant(&mut self, value: Value, location: Location) {
+        let index = self.add_constant(value);
+        self.write_indexed(OpCode::Constant, OpCode::ConstantLong, index, location);
+    }
 
+    /// Adds the value to the constant pool, and returns its index
+    pub fn add_constant(&mut self, value: Value) -> usize {
         const MAX_CONSTANTS: usize = 0x00FF_FFFF;
-        if self.constants.len() <= MAX_CONSTANTS {
-            self.constants.push(value);
+        if self.constants.len() > MAX_CONSTANTS {
+            panic!(
+                "Trying to add more than {} constants to a chunk",
+                MAX_CONSTANTS
+            )
+        }
+
+        self.constants.push(value);
+        self.constants.len() - 1
+    }
 
-            let idx = self.constants.len() - 1;
-            self.write(OpCode::ConstantLong.into(), line);
-            self.write(((idx & 0x00FF_0000) >> 16) as u8, line);
-            self.write(((idx & 0x0000_FF00) >> 8) as u8, line);
-            self.write((idx & 0x0000_00FF) as u8, line);
+    /// Writes `opcode` with a one byte operand if the index fits into it,
+    /// otherwise writes `long_opcode` with a three byte operand
+    pub fn write_indexed(
+        &mut self,
+        opcode: OpCode,
+        long_opcode: OpCode,
+        index: usize,
+        location: Location,
+    ) {
+        if index <= u8::MAX as usize {
+            self.write(opcode.into(), location);
+            self.write(index as u8, location);
 
             return;
         }
 
-        panic!(
-            "Trying to add more than {} constants to a chunk",
-            MAX_CONSTANTS
-        )
+        self.write(long_opcode.into(), location);
+        self.write(((index & 0x00FF_0000) >> 16) as u8, location);
+        self.write(((index & 0x0000_FF00) >> 8) as u8, location);
+        self.write((index & 0x0000_00FF) as u8, location);
+    }
+}
+
+/// Decodes a span table written by `Chunk::write`, `None` if it is malformed
+pub fn decode_spans(mut bytes: &[u8]) -> Option<Vec<SpanStart>> {
+    let mut spans: Vec<SpanStart> = Vec::new();
+    while !bytes.is_empty() {
+        let previous = spans.last().copied().unwrap_or_default();
+        let offset = previous.offset.checked_add(read_varint(&mut bytes)?)?;
+        let column = read_varint(&mut bytes)?;
+        let start = (previous.span.start as i64).checked_add(unzigzag(read_varint(&mut bytes)?))?;
+        let start = usize::try_from(start).ok()?;
+        let end = start.checked_add(read_varint(&mut bytes)?)?;
+
+        spans.push(SpanStart {
+            offset,
+            column,
+            span: Span { start, end },
+        });
+    }
+
+    Some(spans)
+}
+
+/// LEB128, 7 bits per byte, the highest bit is set on every byte but the last
+fn write_varint(bytes: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        bytes.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Option<usize> {
+    let mut value: usize = 0;
+    for shift in (0..usize::BITS).step_by(7) {
+        let (&byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        value |= ((byte & 0x7f) as usize).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Maps signed values to unsigned ones, so small negative values stay small: 0, -1, 1, -2, ... -> 0, 1, 2, 3, ...
+fn zigzag(value: i64) -> usize {
+    ((value << 1) ^ (value >> 63)) as usize
+}
+
+fn unzigzag(value: usize) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Chunk::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(line: usize, column: usize, start: usize, end: usize) -> Location {
+        Location {
+            line,
+            column,
+            span: Span { start, end },
+        }
+    }
+
+    #[test]
+    fn locations_round_trip() {
+        // Spans can go backwards, e.g. the operator of a binary expression is emitted after its operands
+        let locations = [
+            location(1, 1, 0, 3),
+            location(1, 1, 0, 3),
+            location(1, 300, 299, 100_000),
+            location(2, 5, 4, 5),
+            location(2, 5, 4, 5),
+            location(7, 1, 1_000_000, 1_000_001),
+        ];
+
+        let mut chunk = Chunk::new();
+        for location in locations {
+            chunk.write(0, location);
+        }
+
+        assert_eq!(chunk.lines.len(), 3);
+        for (offset, location) in locations.into_iter().enumerate() {
+            assert_eq!(chunk.get_location(offset), location);
+        }
     }
 }
@@ -1,33 +1,132 @@
+use std::fmt::{self, Display};
+
+#[cfg(feature = "debug_print_code")]
+use crate::disassembler::disassemble_chunk;
 use crate::{
     chunk::{Chunk, OpCode},
-    disassembler::disassemble_chunk,
-    scanner::{Scanner, Token, TokenType},
+    diagnostic::{Diagnostic, Label, Severity},
+    object::{Obj, ObjFunction},
+    scanner::{Location, Scanner, Span, Token, TokenType},
+    value::Value,
+    verifier::{self, VerifyError, VerifyErrorKind},
+    vm::VM,
 };
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompileErrorKind {
+    /// The source doesn't follow the grammar, the parser skips to the next statement after these
+    Syntax,
+    /// The source is grammatically valid, but it doesn't make sense, e.g. `this` outside of a class
+    Semantic,
+    /// One of the implementation's limits has been exceeded, e.g. too many local variables
+    Limit,
+}
+
+impl CompileErrorKind {
+    /// The diagnostic code of the errors of this kind
+    pub fn code(self) -> &'static str {
+        match self {
+            CompileErrorKind::Syntax => "syntax",
+            CompileErrorKind::Semantic => "semantic",
+            CompileErrorKind::Limit => "limit",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompileError {
+    pub kind: CompileErrorKind,
+    /// Name of the compiled source, for error messages
+    pub file_name: String,
+    pub message: String,
+    /// The token the error was found at
+    pub token: Token,
+    pub span: Span,
+    /// Other places in the source related to the error
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+}
+
+impl CompileError {
+    fn new(kind: CompileErrorKind, message: &str, token: &Token) -> CompileError {
+        CompileError {
+            kind,
+            file_name: String::new(),
+            message: message.to_owned(),
+            token: token.clone(),
+            span: token.span,
+            labels: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+
+    fn with_label(mut self, location: Location, message: &str) -> CompileError {
+        self.labels.push(Label {
+            location,
+            message: message.to_owned(),
+        });
+        self
+    }
+
+    fn with_note(mut self, note: &str) -> CompileError {
+        self.notes.push(note.to_owned());
+        self
+    }
+
+    pub fn diagnostic(&self) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            code: self.kind.code(),
+            message: self.message.clone(),
+            location: Location {
+                span: self.span,
+                ..self.token.location()
+            },
+            labels: self.labels.clone(),
+            notes: self.notes.clone(),
+        }
+    }
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{}:{}:{}] Error: {}",
+            self.file_name, self.token.line, self.token.column, self.message
+        )
+    }
+}
+
 struct Parser<'a> {
     scanner: Scanner<'a>,
+    /// Errors found so far, they are returned after the whole source has been compiled
+    errors: Vec<CompileError>,
     current: Token,
     previous: Token,
-    had_error: bool,
     in_panic_mode: bool,
 }
 
 impl<'a> Parser<'a> {
-    fn new(scanner: Scanner) -> Parser {
+    fn new(scanner: Scanner<'a>) -> Parser<'a> {
         Parser {
             scanner,
+            errors: Vec::new(),
             // TODO: Replace these two initialisations with a more Rust native way
             current: Token {
                 typ: TokenType::Error,
                 str: "Parser not started yet".to_owned(),
                 line: 0,
+                column: 0,
+                span: Span::default(),
             },
             previous: Token {
                 typ: TokenType::Error,
                 str: "Parser not started yet".to_owned(),
                 line: 0,
+                column: 0,
+                span: Span::default(),
             },
-            had_error: false,
             in_panic_mode: false,
         }
     }
@@ -41,6 +140,20 @@ impl<'a> Parser<'a> {
         self.error_at_current(error_message_on_fail); // TODO: Handle this in a more rust native way (this function should return an error)
     }
 
+    fn check(&self, token_typ: TokenType) -> bool {
+        self.current.typ == token_typ
+    }
+
+    /// Advances if the current token is of the given type
+    fn match_token(&mut self, token_typ: TokenType) -> bool {
+        if !self.check(token_typ) {
+            return false;
+        }
+
+        self.advance();
+        true
+    }
+
     fn advance(&mut self) {
         self.previous = self.current.clone();
 
@@ -54,31 +167,63 @@ impl<'a> Parser<'a> {
         }
     }
 
+    /// Skips tokens until a statement boundary, so we can continue parsing after an error
+    fn synchronize(&mut self) {
+        self.in_panic_mode = false;
+
+        while self.current.typ != TokenType::Eof {
+            if self.previous.typ == TokenType::Semicolon {
+                return;
+            }
+
+            match self.current.typ {
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return => return,
+                _ => {}
+            }
+
+            self.advance();
+        }
+    }
+
+    /// Reports a syntax error at the previous token
     fn error(&mut self, message: &str) {
-        self.error_at(&self.previous.clone(), message)
+        self.error_of_kind(CompileErrorKind::Syntax, message);
     }
 
+    /// Reports a syntax error at the current token
     fn error_at_current(&mut self, message: &str) {
-        self.error_at(&self.current.clone(), message)
+        let error = CompileError::new(CompileErrorKind::Syntax, message, &self.current);
+        self.report(error);
     }
 
-    fn error_at(&mut self, token: &Token, message: &str) {
+    fn error_of_kind(&mut self, kind: CompileErrorKind, message: &str) {
+        let error = CompileError::new(kind, message, &self.previous);
+        self.report(error);
+    }
+
+    /// Records the error, unless we are still recovering from a syntax error,
+    /// in which case it is likely caused by the parser being confused
+    fn report(&mut self, error: CompileError) {
         if self.in_panic_mode {
             return;
         }
-        self.in_panic_mode = true;
-
-        eprint!("[line {}] Error", token.line);
-
-        match token.typ {
-            TokenType::Eof => eprint!(" at end"),
-            TokenType::Error => {}
-            _ => eprint!(" at '{}'", token.str),
+        // Only syntax errors confuse the parser, we can continue reporting errors after the others
+        if error.kind == CompileErrorKind::Syntax {
+            self.in_panic_mode = true;
         }
 
-        eprintln!(": {message}");
+        self.errors.push(error);
+    }
 
-        self.had_error = true;
+    fn had_error(&self) -> bool {
+        !self.errors.is_empty()
     }
 }
 
@@ -115,7 +260,8 @@ impl Precedence {
     }
 }
 
-type ParseFn<'a> = fn(&mut Compiler<'a>);
+/// The `bool` parameter is `can_assign`, whether the parsed expression can be an assignment target
+type ParseFn<'a> = fn(&mut Compiler<'a>, bool);
 
 struct ParseRule<'a> {
     prefix: Option<ParseFn<'a>>,
@@ -128,46 +274,46 @@ const fn get_rule<'a>(token_type: TokenType) -> ParseRule<'a> {
 
     #[rustfmt::skip]
     let parse_rule = match token_type {
-        TokenType::LeftParen    => ParseRule { prefix: Some(Compiler::grouping),infix: None,                    precedence: Non      },
-        TokenType::RightParen   => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::LeftBrace    => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::RightBrace   => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::Comma        => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::Dot          => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::Minus        => ParseRule { prefix: Some(Compiler::unary),   infix: Some(Compiler::binary),  precedence: Term     },
-        TokenType::Plus         => ParseRule { prefix: None,                    infix: Some(Compiler::binary),  precedence: Term     },
-        TokenType::Semicolon    => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::Slash        => ParseRule { prefix: None,                    infix: Some(Compiler::binary),  precedence: Factor   },
-        TokenType::Star         => ParseRule { prefix: None,                    infix: Some(Compiler::binary),  precedence: Factor   },
-        TokenType::Bang         => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::BangEqual    => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::Equal        => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::EqualEqual   => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::Greater      => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::GreaterEqual => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::Less         => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::LessEqual    => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::Identifier   => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::String       => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::Number       => ParseRule { prefix: Some(Compiler::number),  infix: None,                    precedence: Non      },
-        TokenType::And          => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::Class        => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::Else         => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::False        => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::For          => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::Fun          => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::If           => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::Nil          => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::Or           => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::Print        => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::Return       => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::Super        => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::This         => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::True         => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::Var          => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::While        => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::Error        => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
-        TokenType::Eof          => ParseRule { prefix: None,                    infix: None,                    precedence: Non      },
+        TokenType::LeftParen    => ParseRule { prefix: Some(Compiler::grouping),infix: Some(Compiler::call),    precedence: Call       },
+        TokenType::RightParen   => ParseRule { prefix: None,                    infix: None,                    precedence: Non        },
+        TokenType::LeftBrace    => ParseRule { prefix: None,                    infix: None,                    precedence: Non        },
+        TokenType::RightBrace   => ParseRule { prefix: None,                    infix: None,                    precedence: Non        },
+        TokenType::Comma        => ParseRule { prefix: None,                    infix: None,                    precedence: Non        },
+        TokenType::Dot          => ParseRule { prefix: None,                    infix: Some(Compiler::dot),     precedence: Call       },
+        TokenType::Minus        => ParseRule { prefix: Some(Compiler::unary),   infix: Some(Compiler::binary),  precedence: Term       },
+        TokenType::Plus         => ParseRule { prefix: None,                    infix: Some(Compiler::binary),  precedence: Term       },
+        TokenType::Semicolon    => ParseRule { prefix: None,                    infix: None,                    precedence: Non        },
+        TokenType::Slash        => ParseRule { prefix: None,                    infix: Some(Compiler::binary),  precedence: Factor     },
+        TokenType::Star         => ParseRule { prefix: None,                    infix: Some(Compiler::binary),  precedence: Factor     },
+        TokenType::Bang         => ParseRule { prefix: Some(Compiler::unary),   infix: None,                    precedence: Non        },
+        TokenType::BangEqual    => ParseRule { prefix: None,                    infix: Some(Compiler::binary),  precedence: Equality   },
+        TokenType::Equal        => ParseRule { prefix: None,                    infix: None,                    precedence: Non        },
+        TokenType::EqualEqual   => ParseRule { prefix: None,                    infix: Some(Compiler::binary),  precedence: Equality   },
+        TokenType::Greater      => ParseRule { prefix: None,                    infix: Some(Compiler::binary),  precedence: Comparison },
+        TokenType::GreaterEqual => ParseRule { prefix: None,                    infix: Some(Compiler::binary),  precedence: Comparison },
+        TokenType::Less         => ParseRule { prefix: None,                    infix: Some(Compiler::binary),  precedence: Comparison },
+        TokenType::LessEqual    => ParseRule { prefix: None,                    infix: Some(Compiler::binary),  precedence: Comparison },
+        TokenType::Identifier   => ParseRule { prefix: Some(Compiler::variable),infix: None,                    precedence: Non        },
+        TokenType::String       => ParseRule { prefix: Some(Compiler::string),  infix: None,                    precedence: Non        },
+        TokenType::Number       => ParseRule { prefix: Some(Compiler::number),  infix: None,                    precedence: Non        },
+        TokenType::And          => ParseRule { prefix: None,                    infix: Some(Compiler::and_),    precedence: And        },
+        TokenType::Class        => ParseRule { prefix: None,                    infix: None,                    precedence: Non        },
+        TokenType::Else         => ParseRule { prefix: None,                    infix: None,                    precedence: Non        },
+        TokenType::False        => ParseRule { prefix: Some(Compiler::literal), infix: None,                    precedence: Non        },
+        TokenType::For          => ParseRule { prefix: None,                    infix: None,                    precedence: Non        },
+        TokenType::Fun          => ParseRule { prefix: None,                    infix: None,                    precedence: Non        },
+        TokenType::If           => ParseRule { prefix: None,                    infix: None,                    precedence: Non        },
+        TokenType::Nil          => ParseRule { prefix: Some(Compiler::literal), infix: None,                    precedence: Non        },
+        TokenType::Or           => ParseRule { prefix: None,                    infix: Some(Compiler::or_),     precedence: Or         },
+        TokenType::Print        => ParseRule { prefix: None,                    infix: None,                    precedence: Non        },
+        TokenType::Return       => ParseRule { prefix: None,                    infix: None,                    precedence: Non        },
+        TokenType::Super        => ParseRule { prefix: Some(Compiler::super_),  infix: None,                    precedence: Non        },
+        TokenType::This         => ParseRule { prefix: Some(Compiler::this_),   infix: None,                    precedence: Non        },
+        TokenType::True         => ParseRule { prefix: Some(Compiler::literal), infix: None,                    precedence: Non        },
+        TokenType::Var          => ParseRule { prefix: None,                    infix: None,                    precedence: Non        },
+        TokenType::While        => ParseRule { prefix: None,                    infix: None,                    precedence: Non        },
+        TokenType::Error        => ParseRule { prefix: None,                    infix: None,                    precedence: Non        },
+        TokenType::Eof          => ParseRule { prefix: None,                    infix: None,                    precedence: Non        },
     };
 
     parse_rule
@@ -180,88 +326,794 @@ macro_rules! emit_bytes {
     }};
 }
 
+/// Locals are addressed by a single byte stack slot
+const MAX_LOCALS: usize = u8::MAX as usize + 1;
+/// Upvalues are addressed by a single byte index
+pub(crate) const MAX_UPVALUES: usize = u8::MAX as usize + 1;
+
+struct Local {
+    name: Token,
+    /// `None` while the variable is declared, but its initializer has not been compiled yet
+    depth: Option<usize>,
+    /// Captured locals are moved to the heap when they go out of scope, instead of being popped
+    is_captured: bool,
+}
+
+/// A variable captured from an enclosing function
+#[derive(Clone, Copy)]
+struct Upvalue {
+    /// The local slot in the enclosing function if `is_local`, otherwise the enclosing function's upvalue index
+    index: u8,
+    is_local: bool,
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+/// Compilation state of a single function, a new one is started for every nested function declaration
+struct FunctionCompiler {
+    function: *mut ObjFunction,
+    typ: FunctionType,
+
+    /// Locals in scope, their index is the stack slot they occupy at runtime, relative to the call frame
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    /// Number of blocks surrounding the current code, 0 is the global scope
+    scope_depth: usize,
+}
+
+impl FunctionCompiler {
+    fn new(function: *mut ObjFunction, typ: FunctionType) -> FunctionCompiler {
+        let mut locals = Vec::with_capacity(MAX_LOCALS);
+        // The first stack slot is used by the VM for the called function itself,
+        // or for the receiver in methods, which can be accessed as `this`
+        let name = match typ {
+            FunctionType::Method | FunctionType::Initializer => "this",
+            FunctionType::Function | FunctionType::Script => "",
+        };
+        locals.push(Local {
+            name: synthetic_token(name),
+            depth: Some(0),
+            is_captured: false,
+        });
+
+        FunctionCompiler {
+            function,
+            typ,
+            locals,
+            upvalues: Vec::new(),
+            scope_depth: 0,
+        }
+    }
+}
+
+/// Compilation state of a class declaration, a new one is started for every nested class declaration
+struct ClassCompiler {
+    has_superclass: bool,
+}
+
+/// An identifier that doesn't appear in the source, for variables created by the compiler
+fn synthetic_token(text: &str) -> Token {
+    Token {
+        typ: TokenType::Identifier,
+        str: text.to_owned(),
+        line: 0,
+        column: 0,
+        span: Span::default(),
+    }
+}
+
 pub struct Compiler<'a> {
-    current_chunk: Chunk,
     parser: Parser<'a>,
+    /// Objects created during compilation (e.g. string constants) are allocated by the VM
+    vm: &'a mut VM,
+
+    /// The last one is the function currently being compiled, the others are its enclosing functions
+    compilers: Vec<FunctionCompiler>,
+    /// The last one is the innermost class declaration surrounding the current code
+    classes: Vec<ClassCompiler>,
+    /// Whether the script returns the value of its final expression statement, instead of `nil`
+    returns_final_expression: bool,
+    /// Set while starting one of the script's own statements, which is cleared as soon as it is read,
+    /// so statements nested in it, e.g. the body of a loop, are never the script's final expression
+    script_statement: bool,
 }
 
 impl<'a> Compiler<'a> {
-    fn new(parser: Parser) -> Compiler {
+    fn new(parser: Parser<'a>, vm: &'a mut VM, returns_final_expression: bool) -> Compiler<'a> {
         Compiler {
-            current_chunk: Chunk::new(),
             parser,
+            vm,
+            compilers: Vec::new(),
+            classes: Vec::new(),
+            returns_final_expression,
+            script_statement: false,
         }
     }
 
-    pub fn compile(source: &str) -> Result<Chunk, ()> {
+    /// `file_name` is only used in error messages
+    pub fn compile(
+        file_name: &str,
+        source: &str,
+        vm: &mut VM,
+    ) -> Result<*mut ObjFunction, Vec<CompileError>> {
+        Compiler::compile_script(file_name, source, vm, false)
+    }
+
+    /// Compiles the script like `compile`, but it returns the value of its final expression statement,
+    /// whose semicolon is optional, so `1 + 2` evaluates to 3
+    pub fn compile_eval(
+        file_name: &str,
+        source: &str,
+        vm: &mut VM,
+    ) -> Result<*mut ObjFunction, Vec<CompileError>> {
+        Compiler::compile_script(file_name, source, vm, true)
+    }
+
+    fn compile_script(
+        file_name: &str,
+        source: &str,
+        vm: &mut VM,
+        returns_final_expression: bool,
+    ) -> Result<*mut ObjFunction, Vec<CompileError>> {
         let scanner = Scanner::new(source);
         let parser = Parser::new(scanner);
-        let mut compiler = Compiler::new(parser);
+        let mut compiler = Compiler::new(parser, vm, returns_final_expression);
+        compiler.begin_function(FunctionType::Script);
 
         compiler.parser.advance();
-        compiler.expression();
-        compiler
-            .parser
-            .consume(TokenType::Eof, "Expected end of expression.");
+        while !compiler.parser.match_token(TokenType::Eof) {
+            compiler.script_statement = true;
+            compiler.declaration();
+        }
 
-        compiler.emit_return();
+        let (function, _) = compiler.end_function();
 
-        if compiler.parser.had_error {
-            Err(())
+        if compiler.parser.had_error() {
+            let mut errors = compiler.parser.errors;
+            for error in &mut errors {
+                error.file_name = file_name.to_owned();
+            }
+            Err(errors)
         } else {
-            #[cfg(feature = "debug_print_code")]
-            {
-                disassemble_chunk(&compiler.current_chunk, "code");
+            Ok(function)
+        }
+    }
+
+    fn current(&self) -> &FunctionCompiler {
+        self.compilers
+            .last()
+            .expect("to be compiling at least the top-level script")
+    }
+
+    fn current_mut(&mut self) -> &mut FunctionCompiler {
+        self.compilers
+            .last_mut()
+            .expect("to be compiling at least the top-level script")
+    }
+
+    fn current_class_mut(&mut self) -> &mut ClassCompiler {
+        self.classes
+            .last_mut()
+            .expect("to be compiling a class declaration")
+    }
+
+    fn current_chunk(&mut self) -> &mut Chunk {
+        // SAFETY: The function is allocated at the beginning of its compilation, and it is not freed while compiling
+        unsafe { &mut (*self.current().function).chunk }
+    }
+
+    fn begin_function(&mut self, typ: FunctionType) {
+        let function = self.vm.new_function();
+        // The function is only reachable from the compiler until it is finished
+        self.vm.compiler_roots.push(function);
+        if typ != FunctionType::Script {
+            let name = self.vm.copy_string(&self.parser.previous.str);
+            // SAFETY: We have just allocated the function
+            unsafe { (*function).name = name };
+        }
+
+        self.compilers.push(FunctionCompiler::new(function, typ));
+    }
+
+    /// Returns the compiled function, and the variables it captures from its enclosing functions
+    fn end_function(&mut self) -> (*mut ObjFunction, Vec<Upvalue>) {
+        self.emit_return();
+
+        let compiler = self
+            .compilers
+            .pop()
+            .expect("to end a function that has been started");
+        self.vm.compiler_roots.pop();
+
+        // The code of erroneous functions may be inconsistent, but they are never run
+        if !self.parser.had_error() {
+            // SAFETY: The function has been allocated in begin_function
+            let function = unsafe { &mut *compiler.function };
+            match verifier::verify(function) {
+                Ok(max_stack) => function.max_stack = max_stack,
+                Err(VerifyError {
+                    kind: VerifyErrorKind::StackOverflow,
+                    ..
+                }) => self
+                    .parser
+                    .error_of_kind(CompileErrorKind::Limit, "Too many values on the stack."),
+                Err(error) => unreachable!("the compiler to emit valid code, but: {error}"),
             }
-            Ok(compiler.current_chunk)
         }
+
+        #[cfg(feature = "debug_print_code")]
+        if !self.parser.had_error() {
+            // SAFETY: The function has been allocated in begin_function
+            let function = unsafe { &*compiler.function };
+            disassemble_chunk(&function.chunk, function.name());
+        }
+
+        (compiler.function, compiler.upvalues)
     }
 
     //------Parsing------
+    fn declaration(&mut self) {
+        let script_statement = std::mem::take(&mut self.script_statement);
+        if self.parser.match_token(TokenType::Class) {
+            self.class_declaration();
+        } else if self.parser.match_token(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.parser.match_token(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.script_statement = script_statement;
+            self.statement();
+        }
+
+        if self.parser.in_panic_mode {
+            self.parser.synchronize();
+        }
+    }
+
+    fn class_declaration(&mut self) {
+        self.parser
+            .consume(TokenType::Identifier, "Expect class name.");
+        let class_name = self.parser.previous.clone();
+        let name_constant = self.identifier_constant(&class_name);
+        self.declare_variable();
+
+        self.emit_indexed(OpCode::Class, OpCode::ClassLong, name_constant);
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassCompiler {
+            has_superclass: false,
+        });
+
+        if self.parser.match_token(TokenType::Less) {
+            self.parser
+                .consume(TokenType::Identifier, "Expect superclass name.");
+            self.variable(false);
+
+            if class_name.str == self.parser.previous.str {
+                self.parser.error_of_kind(
+                    CompileErrorKind::Semantic,
+                    "A class can't inherit from itself.",
+                );
+            }
+
+            // The superclass is stored in a local, so each class' methods capture their own superclass for `super`
+            self.begin_scope();
+            self.add_local(synthetic_token("super"));
+            self.define_variable(0);
+
+            self.named_variable(&class_name, false);
+            emit_bytes!(self, OpCode::Inherit.into());
+            self.current_class_mut().has_superclass = true;
+        }
+
+        // Load the class, so the methods can be added to it
+        self.named_variable(&class_name, false);
+        self.parser
+            .consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.parser.check(TokenType::RightBrace) && !self.parser.check(TokenType::Eof) {
+            self.method();
+        }
+        self.parser
+            .consume(TokenType::RightBrace, "Expect '}' after class body.");
+        emit_bytes!(self, OpCode::Pop.into());
+
+        let class = self
+            .classes
+            .pop()
+            .expect("to end a class that has been started");
+        if class.has_superclass {
+            self.end_scope();
+        }
+    }
+
+    fn method(&mut self) {
+        self.parser
+            .consume(TokenType::Identifier, "Expect method name.");
+        let name = self.identifier_constant(&self.parser.previous.clone());
+
+        let typ = if self.parser.previous.str == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(typ);
+
+        self.emit_indexed(OpCode::Method, OpCode::MethodLong, name);
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        // A function can refer to itself in its body, so it's initialized before compiling it
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    fn function(&mut self, typ: FunctionType) {
+        self.begin_function(typ);
+        // There is no end_scope, the locals are discarded with the call frame at runtime
+        self.begin_scope();
+
+        self.parser
+            .consume(TokenType::LeftParen, "Expect '(' after function name.");
+        if !self.parser.check(TokenType::RightParen) {
+            loop {
+                // SAFETY: The function is allocated at the beginning of its compilation
+                let function = unsafe { &mut *self.current().function };
+                function.arity += 1;
+                if function.arity > u8::MAX as usize {
+                    let error = CompileError::new(
+                        CompileErrorKind::Limit,
+                        "Can't have more than 255 parameters.",
+                        &self.parser.current,
+                    );
+                    self.parser.report(error);
+                }
+
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+
+                if !self.parser.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.parser
+            .consume(TokenType::RightParen, "Expect ')' after parameters.");
+        self.parser
+            .consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        let (function, upvalues) = self.end_function();
+        let index = self
+            .current_chunk()
+            .add_constant(Value::Obj(function as *mut Obj));
+        self.emit_indexed(OpCode::Closure, OpCode::ClosureLong, index);
+
+        // The closure instruction is followed by the variable length list of the variables to capture
+        for upvalue in upvalues {
+            emit_bytes!(self, upvalue.is_local.into(), upvalue.index);
+        }
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+
+        if self.parser.match_token(TokenType::Equal) {
+            self.expression();
+        } else {
+            emit_bytes!(self, OpCode::Nil.into());
+        }
+        self.parser.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+
+        self.define_variable(global);
+    }
+
+    fn statement(&mut self) {
+        let script_statement = std::mem::take(&mut self.script_statement);
+        if self.parser.match_token(TokenType::Print) {
+            self.print_statement();
+        } else if self.parser.match_token(TokenType::Return) {
+            self.return_statement();
+        } else if self.parser.match_token(TokenType::If) {
+            self.if_statement();
+        } else if self.parser.match_token(TokenType::While) {
+            self.while_statement();
+        } else if self.parser.match_token(TokenType::For) {
+            self.for_statement();
+        } else if self.parser.match_token(TokenType::LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement(script_statement);
+        }
+    }
+
+    fn block(&mut self) {
+        while !self.parser.check(TokenType::RightBrace) && !self.parser.check(TokenType::Eof) {
+            self.declaration();
+        }
+
+        self.parser
+            .consume(TokenType::RightBrace, "Expect '}' after block.");
+    }
+
+    fn return_statement(&mut self) {
+        if self.current().typ == FunctionType::Script {
+            self.parser.error_of_kind(
+                CompileErrorKind::Semantic,
+                "Can't return from top-level code.",
+            );
+        }
+
+        if self.parser.match_token(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.current().typ == FunctionType::Initializer {
+                self.parser.error_of_kind(
+                    CompileErrorKind::Semantic,
+                    "Can't return a value from an initializer.",
+                );
+            }
+
+            self.expression();
+            self.parser
+                .consume(TokenType::Semicolon, "Expect ';' after return value.");
+            emit_bytes!(self, OpCode::Return.into());
+        }
+    }
+
+    fn if_statement(&mut self) {
+        self.parser
+            .consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        self.expression();
+        self.parser
+            .consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        emit_bytes!(self, OpCode::Pop.into()); // Pop the condition
+        self.statement();
+
+        let else_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(then_jump);
+        emit_bytes!(self, OpCode::Pop.into()); // Pop the condition
+
+        if self.parser.match_token(TokenType::Else) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.current_chunk().code.len();
+        self.parser
+            .consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.parser
+            .consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        emit_bytes!(self, OpCode::Pop.into()); // Pop the condition
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        emit_bytes!(self, OpCode::Pop.into()); // Pop the condition
+    }
+
+    fn for_statement(&mut self) {
+        // The initializer's variable is scoped to the loop
+        self.begin_scope();
+
+        self.parser
+            .consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        if self.parser.match_token(TokenType::Semicolon) {
+            // No initializer
+        } else if self.parser.match_token(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.expression_statement(false);
+        }
+
+        let mut loop_start = self.current_chunk().code.len();
+        let mut exit_jump = None;
+        if !self.parser.match_token(TokenType::Semicolon) {
+            self.expression();
+            self.parser
+                .consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            emit_bytes!(self, OpCode::Pop.into()); // Pop the condition
+        }
+
+        if !self.parser.match_token(TokenType::RightParen) {
+            // The increment is compiled before the body, so we jump over it,
+            // and after the body we loop back to it, and then it loops back to the condition
+            let body_jump = self.emit_jump(OpCode::Jump);
+            let increment_start = self.current_chunk().code.len();
+            self.expression();
+            emit_bytes!(self, OpCode::Pop.into());
+            self.parser
+                .consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            emit_bytes!(self, OpCode::Pop.into()); // Pop the condition
+        }
+
+        self.end_scope();
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.parser
+            .consume(TokenType::Semicolon, "Expect ';' after value.");
+        emit_bytes!(self, OpCode::Print.into());
+    }
+
+    fn expression_statement(&mut self, script_statement: bool) {
+        self.expression();
+
+        let is_final_expression = self.returns_final_expression && script_statement;
+        if !(is_final_expression && self.parser.check(TokenType::Eof)) {
+            self.parser
+                .consume(TokenType::Semicolon, "Expect ';' after expression.");
+        }
+
+        if is_final_expression && self.parser.check(TokenType::Eof) {
+            // There are no locals to discard at the top level, the value can be returned right away
+            emit_bytes!(self, OpCode::Return.into());
+        } else {
+            emit_bytes!(self, OpCode::Pop.into());
+        }
+    }
+
     fn expression(&mut self) {
         self.parse_precedence(Precedence::Assignment);
     }
 
-    fn number(&mut self) {
+    fn number(&mut self, _can_assign: bool) {
         let value = self
             .parser
             .previous
             .str
             .parse::<f64>()
             .expect("scanned number token to be f64");
-        self.emit_constant(value);
+        self.emit_constant(Value::Number(value));
+    }
+
+    fn string(&mut self, _can_assign: bool) {
+        // Trim the surrounding quotes
+        let str = &self.parser.previous.str;
+        let string = self.vm.copy_string(&str[1..str.len() - 1]);
+        self.emit_constant(Value::Obj(string as *mut Obj));
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        self.named_variable(&self.parser.previous.clone(), can_assign);
     }
 
-    fn grouping(&mut self) {
+    fn named_variable(&mut self, name: &Token, can_assign: bool) {
+        let is_assignment =
+            |compiler: &mut Compiler| can_assign && compiler.parser.match_token(TokenType::Equal);
+
+        let current = self.compilers.len() - 1;
+        if let Some(slot) = self.resolve_local(current, name) {
+            if is_assignment(self) {
+                self.expression();
+                emit_bytes!(self, OpCode::SetLocal.into(), slot);
+            } else {
+                emit_bytes!(self, OpCode::GetLocal.into(), slot);
+            }
+        } else if let Some(index) = self.resolve_upvalue(current, name) {
+            if is_assignment(self) {
+                self.expression();
+                emit_bytes!(self, OpCode::SetUpvalue.into(), index);
+            } else {
+                emit_bytes!(self, OpCode::GetUpvalue.into(), index);
+            }
+        } else {
+            let index = self.identifier_constant(name);
+            if is_assignment(self) {
+                self.expression();
+                self.emit_indexed(OpCode::SetGlobal, OpCode::SetGlobalLong, index);
+            } else {
+                self.emit_indexed(OpCode::GetGlobal, OpCode::GetGlobalLong, index);
+            }
+        }
+    }
+
+    fn literal(&mut self, _can_assign: bool) {
+        match self.parser.previous.typ {
+            TokenType::False => emit_bytes!(self, OpCode::False.into()),
+            TokenType::Nil => emit_bytes!(self, OpCode::Nil.into()),
+            TokenType::True => emit_bytes!(self, OpCode::True.into()),
+            literal => unreachable!("Illegal literal: `{literal:?}`"),
+        }
+    }
+
+    fn grouping(&mut self, _can_assign: bool) {
         self.expression();
         self.parser
             .consume(TokenType::RightParen, "Expect a ')' after expression");
     }
 
-    fn unary(&mut self) {
+    fn unary(&mut self, _can_assign: bool) {
         let operator_type = self.parser.previous.typ;
+        let operator = self.parser.previous.location();
 
         // Compile the operand
         self.parse_precedence(Precedence::Unary);
 
-        // Emit the operator instruction
-        match operator_type {
-            TokenType::Minus => emit_bytes!(self, OpCode::Negate.into()),
+        // Emit the operator instruction, runtime errors point at the operator, not the operand
+        let opcode = match operator_type {
+            TokenType::Bang => OpCode::Not,
+            TokenType::Minus => OpCode::Negate,
             op => unreachable!("Illegal unary operator: `{op:?}`"),
+        };
+        self.emit_byte_at(opcode.into(), operator);
+    }
+
+    fn and_(&mut self, _can_assign: bool) {
+        // If the left operand is falsey, it is the result, and we skip the right operand
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+
+        emit_bytes!(self, OpCode::Pop.into());
+        self.parse_precedence(Precedence::And);
+
+        self.patch_jump(end_jump);
+    }
+
+    fn or_(&mut self, _can_assign: bool) {
+        // If the left operand is truthy, it is the result, and we skip the right operand
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(else_jump);
+        emit_bytes!(self, OpCode::Pop.into());
+
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
+
+    fn call(&mut self, _can_assign: bool) {
+        let arg_count = self.argument_list();
+        emit_bytes!(self, OpCode::Call.into(), arg_count);
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.parser
+            .consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.identifier_constant(&self.parser.previous.clone());
+
+        if can_assign && self.parser.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_indexed(OpCode::SetProperty, OpCode::SetPropertyLong, name);
+        } else if self.parser.match_token(TokenType::LeftParen) {
+            // Calling a method directly doesn't need to create a bound method
+            let arg_count = self.argument_list();
+            self.emit_indexed(OpCode::Invoke, OpCode::InvokeLong, name);
+            emit_bytes!(self, arg_count);
+        } else {
+            self.emit_indexed(OpCode::GetProperty, OpCode::GetPropertyLong, name);
         }
     }
 
-    fn binary(&mut self) {
+    fn this_(&mut self, _can_assign: bool) {
+        if self.classes.is_empty() {
+            self.parser.error_of_kind(
+                CompileErrorKind::Semantic,
+                "Can't use 'this' outside of a class.",
+            );
+            return;
+        }
+
+        // `this` is a read-only local variable
+        self.variable(false);
+    }
+
+    fn super_(&mut self, _can_assign: bool) {
+        match self.classes.last() {
+            None => self.parser.error_of_kind(
+                CompileErrorKind::Semantic,
+                "Can't use 'super' outside of a class.",
+            ),
+            Some(class) if !class.has_superclass => {
+                let error = CompileError::new(
+                    CompileErrorKind::Semantic,
+                    "Can't use 'super' in a class with no superclass.",
+                    &self.parser.previous,
+                )
+                .with_note("Declare a superclass with `class Name < Superclass { ... }`.");
+                self.parser.report(error);
+            }
+            Some(_) => {}
+        }
+
+        self.parser
+            .consume(TokenType::Dot, "Expect '.' after 'super'.");
+        self.parser
+            .consume(TokenType::Identifier, "Expect superclass method name.");
+        let name = self.identifier_constant(&self.parser.previous.clone());
+
+        // The receiver is needed to bind the method, the superclass to look it up
+        self.named_variable(&synthetic_token("this"), false);
+        if self.parser.match_token(TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            self.named_variable(&synthetic_token("super"), false);
+            self.emit_indexed(OpCode::SuperInvoke, OpCode::SuperInvokeLong, name);
+            emit_bytes!(self, arg_count);
+        } else {
+            self.named_variable(&synthetic_token("super"), false);
+            self.emit_indexed(OpCode::GetSuper, OpCode::GetSuperLong, name);
+        }
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: usize = 0;
+        if !self.parser.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == u8::MAX as usize {
+                    self.parser.error_of_kind(
+                        CompileErrorKind::Limit,
+                        "Can't have more than 255 arguments.",
+                    );
+                }
+                arg_count += 1;
+
+                if !self.parser.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.parser
+            .consume(TokenType::RightParen, "Expect ')' after arguments.");
+
+        arg_count as u8
+    }
+
+    fn binary(&mut self, _can_assign: bool) {
         let operator_type = self.parser.previous.typ;
+        let operator = self.parser.previous.location();
         let rule = get_rule(operator_type);
         self.parse_precedence(rule.precedence.next_higher());
 
-        match operator_type {
-            TokenType::Plus => emit_bytes!(self, OpCode::Add.into()),
-            TokenType::Minus => emit_bytes!(self, OpCode::Subtract.into()),
-            TokenType::Star => emit_bytes!(self, OpCode::Multiply.into()),
-            TokenType::Slash => emit_bytes!(self, OpCode::Divide.into()),
+        // Runtime errors point at the operator, not the right operand
+        let opcodes: &[OpCode] = match operator_type {
+            TokenType::BangEqual => &[OpCode::Equal, OpCode::Not],
+            TokenType::EqualEqual => &[OpCode::Equal],
+            TokenType::Greater => &[OpCode::Greater],
+            TokenType::GreaterEqual => &[OpCode::Less, OpCode::Not],
+            TokenType::Less => &[OpCode::Less],
+            TokenType::LessEqual => &[OpCode::Greater, OpCode::Not],
+            TokenType::Plus => &[OpCode::Add],
+            TokenType::Minus => &[OpCode::Subtract],
+            TokenType::Star => &[OpCode::Multiply],
+            TokenType::Slash => &[OpCode::Divide],
             op => unreachable!("Illegal binary operator: `{op:?}`"),
+        };
+        for &opcode in opcodes {
+            self.emit_byte_at(opcode.into(), operator);
         }
     }
 
@@ -272,28 +1124,261 @@ impl<'a> Compiler<'a> {
             return;
         };
 
-        prefix_rule(self);
+        // Only allow assignment if we are parsing a low enough precedence expression,
+        // otherwise `a * b = c` would be parsed as `a * (b = c)`
+        let can_assign = precedence <= Precedence::Assignment;
+        prefix_rule(self, can_assign);
 
         while precedence <= get_rule(self.parser.current.typ).precedence {
             self.parser.advance();
             let infix_rule = get_rule(self.parser.previous.typ)
                 .infix
                 .expect("infix rule to exist for infix operation");
-            infix_rule(self);
+            infix_rule(self, can_assign);
+        }
+
+        // If the `=` was not consumed as part of an assignment, then the target was not assignable
+        if can_assign && self.parser.match_token(TokenType::Equal) {
+            self.parser.error("Invalid assignment target.");
+        }
+    }
+
+    /// Returns the index of the variable name's constant for globals, and a dummy 0 for locals
+    fn parse_variable(&mut self, error_message: &str) -> usize {
+        self.parser.consume(TokenType::Identifier, error_message);
+
+        self.declare_variable();
+        if self.current().scope_depth > 0 {
+            // Locals are not looked up by name at runtime
+            return 0;
+        }
+
+        self.identifier_constant(&self.parser.previous.clone())
+    }
+
+    fn identifier_constant(&mut self, name: &Token) -> usize {
+        let string = self.vm.copy_string(&name.str);
+        self.current_chunk()
+            .add_constant(Value::Obj(string as *mut Obj))
+    }
+
+    /// Records the existence of local variables, globals are late bound, so they are not declared
+    fn declare_variable(&mut self) {
+        let current = self.current();
+        if current.scope_depth == 0 {
+            return;
+        }
+
+        let name = self.parser.previous.clone();
+        let previous_declaration = current
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= current.scope_depth))
+            .find(|local| local.name.str == name.str);
+        if let Some(previous_declaration) = previous_declaration {
+            let error = CompileError::new(
+                CompileErrorKind::Semantic,
+                "Already a variable with this name in this scope.",
+                &name,
+            )
+            .with_label(
+                previous_declaration.name.location(),
+                "previously declared here",
+            );
+            self.parser.report(error);
+        }
+
+        self.add_local(name);
+    }
+
+    fn add_local(&mut self, name: Token) {
+        if self.current().locals.len() == MAX_LOCALS {
+            self.parser.error_of_kind(
+                CompileErrorKind::Limit,
+                "Too many local variables in function.",
+            );
+            return;
+        }
+
+        self.current_mut().locals.push(Local {
+            name,
+            depth: None,
+            is_captured: false,
+        });
+    }
+
+    /// Returns the stack slot of the local variable in the `compiler_index`th function,
+    /// or `None` if it is not a local of that function
+    fn resolve_local(&mut self, compiler_index: usize, name: &Token) -> Option<u8> {
+        let (slot, local) = self.compilers[compiler_index]
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name.str == name.str)?;
+
+        if local.depth.is_none() {
+            self.parser.error_of_kind(
+                CompileErrorKind::Semantic,
+                "Can't read local variable in its own initializer.",
+            );
+        }
+
+        Some(slot as u8)
+    }
+
+    /// Returns the upvalue index of a variable captured by the `compiler_index`th function
+    /// from one of its enclosing functions, or `None` if it is a global
+    fn resolve_upvalue(&mut self, compiler_index: usize, name: &Token) -> Option<u8> {
+        // The top-level script has no enclosing function
+        let enclosing = compiler_index.checked_sub(1)?;
+
+        if let Some(local) = self.resolve_local(enclosing, name) {
+            self.compilers[enclosing].locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(compiler_index, local, true));
+        }
+
+        // Capture it from further out, every function in between captures it too
+        let upvalue = self.resolve_upvalue(enclosing, name)?;
+        Some(self.add_upvalue(compiler_index, upvalue, false))
+    }
+
+    fn add_upvalue(&mut self, compiler_index: usize, index: u8, is_local: bool) -> u8 {
+        let compiler = &mut self.compilers[compiler_index];
+
+        // Closures capture a variable only once, even if it is referenced multiple times
+        if let Some(existing) = compiler
+            .upvalues
+            .iter()
+            .position(|upvalue| upvalue.index == index && upvalue.is_local == is_local)
+        {
+            return existing as u8;
+        }
+
+        if compiler.upvalues.len() == MAX_UPVALUES {
+            self.parser.error_of_kind(
+                CompileErrorKind::Limit,
+                "Too many closure variables in function.",
+            );
+            return 0;
+        }
+
+        compiler.upvalues.push(Upvalue { index, is_local });
+        // SAFETY: The function is allocated at the beginning of its compilation
+        unsafe { (*compiler.function).upvalue_count = compiler.upvalues.len() };
+        (compiler.upvalues.len() - 1) as u8
+    }
+
+    fn mark_initialized(&mut self) {
+        let current = self.current_mut();
+        if current.scope_depth == 0 {
+            // Globals don't have a local to mark
+            return;
+        }
+
+        let local = current
+            .locals
+            .last_mut()
+            .expect("a local to be declared before it is initialized");
+        local.depth = Some(current.scope_depth);
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        if self.current().scope_depth > 0 {
+            // The initializer's value is already in the local's stack slot
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit_indexed(OpCode::DefineGlobal, OpCode::DefineGlobalLong, global);
+    }
+
+    //------Scopes------
+    fn begin_scope(&mut self) {
+        self.current_mut().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.current_mut().scope_depth -= 1;
+
+        while let Some(local) = self.current().locals.last()
+            && local
+                .depth
+                .is_some_and(|depth| depth > self.current().scope_depth)
+        {
+            if local.is_captured {
+                emit_bytes!(self, OpCode::CloseUpvalue.into());
+            } else {
+                emit_bytes!(self, OpCode::Pop.into());
+            }
+            self.current_mut().locals.pop();
         }
     }
 
     //------Emission------
     fn emit_byte(&mut self, byte: u8) {
-        self.current_chunk.write(byte, self.parser.previous.line);
+        self.emit_byte_at(byte, self.parser.previous.location());
+    }
+
+    fn emit_byte_at(&mut self, byte: u8, location: Location) {
+        self.current_chunk().write(byte, location);
+    }
+
+    /// Emits a jump with a placeholder operand, and returns the offset of the operand to patch later
+    fn emit_jump(&mut self, instruction: OpCode) -> usize {
+        emit_bytes!(self, instruction.into(), 0xff, 0xff);
+        self.current_chunk().code.len() - 2
+    }
+
+    /// Sets the jump's operand to jump to the current end of the chunk
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to adjust for the jump offset operand itself
+        let jump = self.current_chunk().code.len() - offset - 2;
+
+        if jump > u16::MAX as usize {
+            self.parser
+                .error_of_kind(CompileErrorKind::Limit, "Too much code to jump over.");
+        }
+
+        let code = &mut self.current_chunk().code;
+        code[offset] = ((jump >> 8) & 0xff) as u8;
+        code[offset + 1] = (jump & 0xff) as u8;
     }
 
+    fn emit_loop(&mut self, loop_start: usize) {
+        emit_bytes!(self, OpCode::Loop.into());
+
+        // +2 to adjust for the loop offset operand itself
+        let offset = self.current_chunk().code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.parser
+                .error_of_kind(CompileErrorKind::Limit, "Loop body too large.");
+        }
+
+        emit_bytes!(self, ((offset >> 8) & 0xff) as u8, (offset & 0xff) as u8);
+    }
+
+    /// Emits an implicit `return nil;`
     fn emit_return(&mut self) {
+        if self.current().typ == FunctionType::Initializer {
+            // Initializers implicitly return the new instance
+            emit_bytes!(self, OpCode::GetLocal.into(), 0);
+        } else {
+            emit_bytes!(self, OpCode::Nil.into());
+        }
+
         emit_bytes!(self, OpCode::Return.into());
     }
 
-    fn emit_constant(&mut self, value: f64) {
-        self.current_chunk
-            .write_constant(value, self.parser.previous.line);
+    fn emit_indexed(&mut self, opcode: OpCode, long_opcode: OpCode, index: usize) {
+        let location = self.parser.previous.location();
+        self.current_chunk()
+            .write_indexed(opcode, long_opcode, index, location);
+    }
+
+    fn emit_constant(&mut self, value: Value) {
+        let location = self.parser.previous.location();
+        self.current_chunk().write_constant(value, location);
     }
 }
@@ -0,0 +1,196 @@
+use std::io::{self, IsTerminal};
+
+use serde::Serialize;
+
+use crate::scanner::Location;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    #[allow(dead_code)] // Nothing reports warnings yet
+    Warning,
+}
+
+/// Points at a secondary place in the source, which is related to the diagnostic
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub location: Location,
+    pub message: String,
+}
+
+/// A problem found in the source, with everything needed to show the user where it is
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Stable identifier of the kind of problem, for tools consuming the diagnostics
+    pub code: &'static str,
+    pub message: String,
+    pub location: Location,
+    pub labels: Vec<Label>,
+    /// Extra explanations, shown after the source snippets
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    /// A problem with a whole file rather than a place in its source, e.g. it could not be read.
+    /// It is reported at line 0, column 0.
+    pub fn file_error(code: &'static str, message: String) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            code,
+            message,
+            location: Location::default(),
+            labels: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+}
+
+const RESET: &str = "\x1b[0m";
+const BOLD_RED: &str = "\x1b[1;31m";
+const BOLD_YELLOW: &str = "\x1b[1;33m";
+const BOLD_BLUE: &str = "\x1b[1;34m";
+
+/// Prints the diagnostics to stderr, colored if it is a terminal
+pub fn emit(diagnostics: &[Diagnostic], file_name: &str, source: &str) {
+    let color = io::stderr().is_terminal();
+    for diagnostic in diagnostics {
+        eprint!("{}", render(diagnostic, file_name, source, color));
+    }
+}
+
+/// One entry of the JSON output, tools rely on these fields, so they must not change
+#[derive(Serialize)]
+struct JsonDiagnostic<'a> {
+    file: &'a str,
+    line: usize,
+    column: usize,
+    severity: &'static str,
+    code: &'static str,
+    message: &'a str,
+}
+
+/// Renders the diagnostics as a JSON array, for editors and CI tools:
+/// ```text
+/// [{"file":"main.lox","line":5,"column":9,"severity":"error","code":"semantic","message":"..."}]
+/// ```
+pub fn to_json(diagnostics: &[Diagnostic], file_name: &str) -> String {
+    let diagnostics: Vec<_> = diagnostics
+        .iter()
+        .map(|diagnostic| JsonDiagnostic {
+            file: file_name,
+            line: diagnostic.location.line,
+            column: diagnostic.location.column,
+            severity: match diagnostic.severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+            },
+            code: diagnostic.code,
+            message: &diagnostic.message,
+        })
+        .collect();
+
+    serde_json::to_string(&diagnostics).expect("diagnostics to be serializable")
+}
+
+/// Renders the diagnostic with the source lines it points at in source order, underlining the exact spans:
+/// ```text
+/// [main.lox:5:9] Error: Already a variable with this name in this scope.
+///   |
+/// 4 |     var b = 1;
+///   |         - previously declared here
+/// 5 |     var b = 2;
+///   |         ^
+///   = note: ...
+/// ```
+pub fn render(diagnostic: &Diagnostic, file_name: &str, source: &str, color: bool) -> String {
+    let style = |style: &'static str| if color { style } else { "" };
+    let (severity, severity_style) = match diagnostic.severity {
+        Severity::Error => ("Error", BOLD_RED),
+        Severity::Warning => ("Warning", BOLD_YELLOW),
+    };
+    let gutter_style = style(BOLD_BLUE);
+    let reset = style(RESET);
+
+    let location = diagnostic.location;
+    let gutter_width = diagnostic
+        .labels
+        .iter()
+        .map(|label| label.location.line)
+        .chain([location.line])
+        .max()
+        .unwrap_or_default()
+        .to_string()
+        .len();
+    let blank_gutter = " ".repeat(gutter_width);
+
+    let mut lines = vec![
+        format!(
+            "[{file_name}:{}:{}] {}{severity}{reset}: {}",
+            location.line,
+            location.column,
+            style(severity_style),
+            diagnostic.message
+        ),
+        format!("{blank_gutter} {gutter_style}|{reset}"),
+    ];
+
+    let mut snippets: Vec<_> = [(location, '^', severity_style, "")]
+        .into_iter()
+        .chain(
+            diagnostic
+                .labels
+                .iter()
+                .map(|label| (label.location, '-', BOLD_BLUE, label.message.as_str())),
+        )
+        .collect();
+    snippets.sort_by_key(|(location, ..)| (location.line, location.span.start));
+    for (location, underline, underline_style, message) in snippets {
+        // The end of a file ending with a newline is on an empty line, synthetic tokens have no line to show
+        let Some(line) = location
+            .line
+            .checked_sub(1)
+            .and_then(|index| source.lines().nth(index))
+            .or_else(|| (location.line > 0 && location.span.start == source.len()).then_some(""))
+        else {
+            continue;
+        };
+        lines.push(
+            format!(
+                "{gutter_style}{:>gutter_width$} |{reset} {line}",
+                location.line
+            )
+            .trim_end()
+            .to_owned(),
+        );
+
+        // Keep tabs, so the underline is aligned with the line above
+        let indent: String = line
+            .chars()
+            .take(location.column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        // Multi-line spans are only underlined until the end of their first line
+        let width = source
+            .get(location.span.start..location.span.end)
+            .and_then(|text| text.lines().next())
+            .map_or(0, |text| text.chars().count())
+            .max(1);
+        let mut underline = underline.to_string().repeat(width);
+        if !message.is_empty() {
+            underline = format!("{underline} {message}");
+        }
+        lines.push(format!(
+            "{blank_gutter} {gutter_style}|{reset} {indent}{}{underline}{reset}",
+            style(underline_style)
+        ));
+    }
+
+    for note in &diagnostic.notes {
+        lines.push(format!(
+            "{blank_gutter} {gutter_style}={reset} note: {note}"
+        ));
+    }
+
+    lines.join("\n") + "\n"
+}
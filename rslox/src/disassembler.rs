@@ -1,6 +1,7 @@
 use crate::{
     chunk::{Chunk, OpCode},
-    value::print_value,
+    object::{ObjFunction, as_obj},
+    value::{Value, print_value},
 };
 
 pub fn disassemble_chunk(chunk: &Chunk, name: &str) {
@@ -14,20 +15,34 @@ pub fn disassemble_chunk(chunk: &Chunk, name: &str) {
 
 pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
     print!("{offset:04} ");
-    if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
+    let line = chunk.get_line(offset);
+    if offset > 0 && line == chunk.get_line(offset - 1) {
         print!("   | ");
     } else {
-        print!("{:>4} ", chunk.lines[offset]);
+        print!("{line:>4} ");
     }
 
     if let Ok(instruction) = OpCode::try_from(chunk.code[offset]) {
         use OpCode::*;
 
         match instruction {
-            Return | Negate | Add | Subtract | Multiply | Divide => {
+            Return | Negate | Add | Subtract | Multiply | Divide | Nil | True | False | Not
+            | Equal | Greater | Less | Print | Pop | CloseUpvalue | Inherit => {
                 simple_instruction(instruction, offset)
             }
-            Constant | ConstantLong => constant_instruction(instruction, chunk, offset),
+            Constant | DefineGlobal | GetGlobal | SetGlobal | Class | GetProperty | SetProperty
+            | Method | GetSuper => constant_instruction(instruction, chunk, offset),
+            ConstantLong | DefineGlobalLong | GetGlobalLong | SetGlobalLong | ClassLong
+            | GetPropertyLong | SetPropertyLong | MethodLong | GetSuperLong => {
+                constant_long_instruction(instruction, chunk, offset)
+            }
+            GetLocal | SetLocal | Call => byte_instruction(instruction, chunk, offset),
+            Jump | JumpIfFalse => jump_instruction(instruction, true, chunk, offset),
+            Loop => jump_instruction(instruction, false, chunk, offset),
+            Closure | ClosureLong => closure_instruction(instruction, chunk, offset),
+            GetUpvalue | SetUpvalue => byte_instruction(instruction, chunk, offset),
+            Invoke | SuperInvoke => invoke_instruction(instruction, chunk, offset),
+            InvokeLong | SuperInvokeLong => invoke_long_instruction(instruction, chunk, offset),
         }
     } else {
         println!("Unknown opcode {}", chunk.code[offset]);
@@ -40,26 +55,95 @@ fn simple_instruction(opcode: OpCode, offset: usize) -> usize {
     offset + 1
 }
 
+fn byte_instruction(opcode: OpCode, chunk: &Chunk, offset: usize) -> usize {
+    let slot = chunk.code[offset + 1];
+    println!("{opcode:-16?} {slot:04}");
+
+    offset + 2
+}
+
+/// Shows both the jump's offset and the offset of the instruction it jumps to
+fn jump_instruction(opcode: OpCode, is_forward: bool, chunk: &Chunk, offset: usize) -> usize {
+    let jump = (chunk.code[offset + 1] as usize) << 8 | (chunk.code[offset + 2] as usize);
+    let next = offset + 3;
+    let target = if is_forward { next + jump } else { next - jump };
+    println!("{opcode:-16?} {offset:04} -> {target:04}");
+
+    next
+}
+
 fn constant_instruction(opcode: OpCode, chunk: &Chunk, offset: usize) -> usize {
-    match opcode {
-        OpCode::Constant => {
-            let constant = chunk.code[offset + 1];
-            print!("{opcode:-16?} {constant:04} '");
-            print_value(chunk.constants[constant as usize]);
-            println!("'");
-
-            offset + 2
-        }
-        OpCode::ConstantLong => {
-            let constant = (chunk.code[offset + 1] as usize) << 16
+    let constant = chunk.code[offset + 1];
+    print!("{opcode:-16?} {constant:04} '");
+    print_value(chunk.constants[constant as usize]);
+    println!("'");
+
+    offset + 2
+}
+
+fn constant_long_instruction(opcode: OpCode, chunk: &Chunk, offset: usize) -> usize {
+    let constant = (chunk.code[offset + 1] as usize) << 16
+        | (chunk.code[offset + 2] as usize) << 8
+        | (chunk.code[offset + 3] as usize);
+    print!("{opcode:-16?} {constant:04} '");
+    print_value(chunk.constants[constant]);
+    println!("'");
+
+    offset + 4
+}
+
+/// Shows the method name constant and the argument count
+fn invoke_instruction(opcode: OpCode, chunk: &Chunk, offset: usize) -> usize {
+    let constant = chunk.code[offset + 1];
+    let arg_count = chunk.code[offset + 2];
+    print!("{opcode:-16?} ({arg_count} args) {constant:04} '");
+    print_value(chunk.constants[constant as usize]);
+    println!("'");
+
+    offset + 3
+}
+
+fn invoke_long_instruction(opcode: OpCode, chunk: &Chunk, offset: usize) -> usize {
+    let constant = (chunk.code[offset + 1] as usize) << 16
+        | (chunk.code[offset + 2] as usize) << 8
+        | (chunk.code[offset + 3] as usize);
+    let arg_count = chunk.code[offset + 4];
+    print!("{opcode:-16?} ({arg_count} args) {constant:04} '");
+    print_value(chunk.constants[constant]);
+    println!("'");
+
+    offset + 5
+}
+
+/// Shows the function constant, followed by the variable length list of the variables it captures
+fn closure_instruction(opcode: OpCode, chunk: &Chunk, offset: usize) -> usize {
+    let (constant, mut offset) = match opcode {
+        OpCode::Closure => (
+            chunk.code[offset + 1] as usize,
+            constant_instruction(opcode, chunk, offset),
+        ),
+        OpCode::ClosureLong => (
+            (chunk.code[offset + 1] as usize) << 16
                 | (chunk.code[offset + 2] as usize) << 8
-                | (chunk.code[offset + 3] as usize);
-            print!("{opcode:-16?} {constant:04} '");
-            print_value(chunk.constants[constant]);
-            println!("'");
+                | (chunk.code[offset + 3] as usize),
+            constant_long_instruction(opcode, chunk, offset),
+        ),
+        _ => unreachable!("should only call closure_instruction on Closure or ClosureLong"),
+    };
 
-            offset + 4
-        }
-        _ => unreachable!("should only call constant_instruction on Constant or ConstantLong"),
+    let Value::Obj(function) = chunk.constants[constant] else {
+        unreachable!("closure constant to be a function");
+    };
+    // SAFETY: The compiler only emits closure instructions for function constants
+    let function = unsafe { as_obj::<ObjFunction>(function) };
+    for _ in 0..function.upvalue_count {
+        let is_local = chunk.code[offset] == 1;
+        let index = chunk.code[offset + 1];
+        let kind = if is_local { "local" } else { "upvalue" };
+        println!("{offset:04}    |                     {kind} {index}");
+
+        offset += 2;
     }
+
+    offset
 }
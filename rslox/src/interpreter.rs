@@ -0,0 +1,159 @@
+use std::fmt::{self, Display};
+
+use crate::{
+    compiler::CompileError,
+    value::{FromLox, sealed::Token},
+    vm::{InterpretResult, RuntimeError, VM},
+};
+
+/// Name of the evaluated source in error messages
+const EVAL_FILE_NAME: &str = "<eval>";
+
+#[derive(Debug, Clone)]
+pub enum LoxError {
+    Compile(Vec<CompileError>),
+    Runtime(RuntimeError),
+    /// The script ran, but its result could not be converted to the requested type
+    ResultType {
+        expected: String,
+    },
+}
+
+impl Display for LoxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoxError::Compile(errors) => {
+                let errors: Vec<_> = errors.iter().map(CompileError::to_string).collect();
+                write!(f, "{}", errors.join("\n"))
+            }
+            LoxError::Runtime(error) => write!(f, "{error}"),
+            LoxError::ResultType { expected } => write!(f, "Expected the result to be {expected}."),
+        }
+    }
+}
+
+impl std::error::Error for LoxError {}
+
+/// Runs Lox code from Rust, the globals defined by one call are visible to the next ones,
+/// so a script can define functions once, and they can be called by later calls to `eval`
+pub struct Interpreter {
+    vm: VM,
+}
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        Interpreter { vm: VM::new() }
+    }
+
+    /// Runs the source as a script, and converts the value of its final expression statement, or `nil` if there is none.
+    /// The value is converted before anything else can be allocated, because its objects are owned by the interpreter.
+    /// `eval::<Value>` returns the result as it is, unless it is an object.
+    pub fn eval<T: FromLox>(&mut self, source: &str) -> Result<T, LoxError> {
+        match self.vm.evaluate(EVAL_FILE_NAME, source) {
+            InterpretResult::Ok => {
+                let result =
+                    T::from_lox(self.vm.peek(0), Token(())).ok_or_else(|| LoxError::ResultType {
+                        expected: T::type_name(),
+                    });
+                self.vm.pop();
+                result
+            }
+            InterpretResult::CompileError(errors) => Err(LoxError::Compile(errors)),
+            InterpretResult::RuntimeError(error) => Err(LoxError::Runtime(error)),
+        }
+    }
+
+    /// Runs the source as a script, ignoring its result
+    pub fn run(&mut self, source: &str) -> Result<(), LoxError> {
+        match self.vm.interpret(EVAL_FILE_NAME, source) {
+            InterpretResult::Ok => Ok(()),
+            InterpretResult::CompileError(errors) => Err(LoxError::Compile(errors)),
+            InterpretResult::RuntimeError(error) => Err(LoxError::Runtime(error)),
+        }
+    }
+
+    pub fn vm(&mut self) -> &mut VM {
+        &mut self.vm
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Interpreter::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::Value;
+
+    #[test]
+    fn eval_returns_the_final_expression() {
+        let mut interpreter = Interpreter::new();
+
+        assert_eq!(interpreter.eval::<f64>("var a = 1; a + 2").unwrap(), 3.0);
+        assert_eq!(interpreter.eval::<String>("\"a\" + \"b\";").unwrap(), "ab");
+        assert_eq!(interpreter.eval::<Value>("var b = 1;").unwrap(), Value::Nil);
+    }
+
+    #[test]
+    fn eval_runs_nested_statements_to_the_end() {
+        let mut interpreter = Interpreter::new();
+
+        let result = interpreter.eval::<Value>("var x = 3; while (x > 0) x = x - 1;");
+        assert_eq!(result.unwrap(), Value::Nil);
+        assert_eq!(interpreter.eval::<f64>("x").unwrap(), 0.0);
+        assert_eq!(
+            interpreter.eval::<Value>("if (true) { 1; }").unwrap(),
+            Value::Nil
+        );
+    }
+
+    #[test]
+    fn globals_are_kept_between_calls() {
+        let mut interpreter = Interpreter::new();
+        interpreter.run("fun double(n) { return n * 2; }").unwrap();
+
+        assert_eq!(interpreter.eval::<f64>("double(21)").unwrap(), 42.0);
+    }
+
+    #[test]
+    fn eval_rejects_results_of_the_wrong_type() {
+        let mut interpreter = Interpreter::new();
+
+        assert!(matches!(
+            interpreter.eval::<f64>("\"a\""),
+            Err(LoxError::ResultType { expected }) if expected == "a number"
+        ));
+        // Objects can't outlive the call as values
+        assert!(matches!(
+            interpreter.eval::<Value>("\"a\""),
+            Err(LoxError::ResultType { .. })
+        ));
+    }
+
+    #[test]
+    fn errors_name_the_evaluated_source() {
+        let mut interpreter = Interpreter::new();
+
+        let error = interpreter.run("1 +").unwrap_err();
+        assert!(matches!(error, LoxError::Compile(_)));
+        assert!(error.to_string().starts_with("[<eval>:1:4] Error:"));
+
+        let error = interpreter.run("nil + 1;").unwrap_err();
+        assert!(matches!(error, LoxError::Runtime(_)));
+        assert!(error.to_string().contains("[<eval>:1:5]"));
+    }
+
+    #[test]
+    fn closures_outlive_runtime_errors() {
+        let mut interpreter = Interpreter::new();
+        let result = interpreter.run(
+            "var g; fun f() { var x = 42; fun h() { return x; } g = h; return nil + 1; } f();",
+        );
+        assert!(matches!(result, Err(LoxError::Runtime(_))));
+
+        assert_eq!(interpreter.eval::<f64>("g()").unwrap(), 42.0);
+    }
+}
@@ -0,0 +1,25 @@
+//! A bytecode virtual machine for Lox, which can be embedded in Rust programs through `Interpreter`
+
+pub mod bytecode;
+pub mod chunk;
+pub mod compiler;
+pub mod diagnostic;
+#[cfg(any(feature = "debug_print_code", feature = "debug_trace_execution"))]
+mod disassembler;
+mod interpreter;
+mod memory;
+mod native;
+mod object;
+mod scanner;
+mod table;
+mod utils;
+pub mod value;
+mod verifier;
+pub mod vm;
+
+pub use chunk::Chunk;
+pub use compiler::Compiler;
+pub use interpreter::{Interpreter, LoxError};
+pub use native::{IntoNative, NativeResult};
+pub use value::{FromLox, IntoLox, Value};
+pub use vm::{InterpretResult, VM};
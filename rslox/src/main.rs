@@ -1,34 +1,77 @@
 use std::{
     fs,
     io::{self, Write},
+    path::Path,
     process::exit,
 };
 
 use clap::Parser;
 
-use crate::vm::VM;
-
-mod chunk;
-mod compiler;
-mod disassembler;
-mod scanner;
-mod utils;
-mod value;
-mod vm;
+use rslox::{
+    InterpretResult, VM, bytecode,
+    compiler::{self, CompileError},
+    diagnostic::{self, Diagnostic},
+    vm::RuntimeError,
+};
 
 #[derive(clap::Parser)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
-    /// File to run
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// File to run, either a Lox script or a compiled `.loxc` file
     file: Option<String>,
 }
 
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Run a Lox script or a compiled `.loxc` file
+    Run {
+        file: String,
+        /// Format of the errors printed to stderr
+        #[arg(long, value_enum, default_value_t)]
+        format: Format,
+    },
+    /// Report the compile errors of a Lox script without running it
+    Check {
+        file: String,
+        /// Format of the errors, JSON is printed to stdout
+        #[arg(long, value_enum, default_value_t)]
+        format: Format,
+    },
+    /// Compile a Lox script to a `.loxc` bytecode file, which can be run without parsing it again
+    Compile {
+        file: String,
+        /// Defaults to the script's path with a `.loxc` extension
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Default)]
+enum Format {
+    /// Messages with the source lines they point at
+    #[default]
+    Text,
+    /// An array of `{file, line, column, severity, code, message}` objects
+    Json,
+}
+
 fn main() {
     let cli = Cli::parse();
 
     let vm = VM::new();
 
+    match cli.command {
+        Some(Command::Run { file, format }) => run_file(vm, &file, format),
+        Some(Command::Check { file, format }) => check_file(vm, &file, format),
+        Some(Command::Compile { file, output }) => compile_file(vm, &file, output),
+        None => {}
+    }
+
     if let Some(file_path) = cli.file {
-        run_file(vm, &file_path);
+        run_file(vm, &file_path, Format::Text);
     }
 
     repl(vm)
@@ -49,28 +92,185 @@ fn repl(vm: VM) {
 
         println!();
 
-        interpret(&mut vm, &line);
+        interpret(&mut vm, "<repl>", &line, Format::Text);
     }
 }
 
-fn run_file(vm: VM, file_path: &str) -> ! {
+fn run_file(vm: VM, file_path: &str, format: Format) -> ! {
     let mut vm = vm;
-    let source = match fs::read_to_string(file_path) {
-        Ok(source) => source,
-        Err(err) => {
-            eprintln!("Could not open file {file_path}: {err}");
-            exit(74);
+    let bytes =
+        read_file(file_path).unwrap_or_else(|error| exit_with_file_error(error, file_path, format));
+
+    let result = if bytecode::is_bytecode(&bytes) {
+        match vm.interpret_bytecode(&bytes) {
+            Ok(result) => {
+                // Compiled scripts can only fail at runtime, which doesn't need the source
+                report_result(&result, file_path, "", format);
+                result
+            }
+            Err(err) => exit_with_file_error(
+                FileError {
+                    code: "load",
+                    message: err.to_string(),
+                    text: format!("Could not load {file_path}: {err}"),
+                    status: 65,
+                },
+                file_path,
+                format,
+            ),
         }
+    } else {
+        let source = read_source(file_path, bytes)
+            .unwrap_or_else(|error| exit_with_file_error(error, file_path, format));
+        interpret(&mut vm, file_path, &source, format)
     };
-    let result = interpret(&mut vm, &source);
 
     match result {
-        vm::InterpretResult::Ok => exit(0),
-        vm::InterpretResult::CompileError => exit(65),
-        vm::InterpretResult::RuntimeError => exit(70),
+        InterpretResult::Ok => exit(0),
+        InterpretResult::CompileError(_) => exit(65),
+        InterpretResult::RuntimeError(_) => exit(70),
+    }
+}
+
+fn check_file(vm: VM, file_path: &str, format: Format) -> ! {
+    let mut vm = vm;
+    let source = match read_file(file_path).and_then(|bytes| read_source(file_path, bytes)) {
+        Ok(source) => source,
+        Err(error) => {
+            match format {
+                Format::Text => eprintln!("{}", error.text),
+                Format::Json => println!("{}", error.to_json(file_path)),
+            }
+            exit(error.status);
+        }
+    };
+
+    let errors = compiler::Compiler::compile(file_path, &source, &mut vm)
+        .err()
+        .unwrap_or_default();
+    match format {
+        Format::Text => report_compile_errors(&errors, file_path, &source),
+        Format::Json => {
+            let diagnostics: Vec<_> = errors.iter().map(CompileError::diagnostic).collect();
+            println!("{}", diagnostic::to_json(&diagnostics, file_path));
+        }
+    }
+
+    exit(if errors.is_empty() { 0 } else { 65 })
+}
+
+fn compile_file(vm: VM, file_path: &str, output: Option<String>) -> ! {
+    let mut vm = vm;
+    let source = read_file(file_path)
+        .and_then(|bytes| read_source(file_path, bytes))
+        .unwrap_or_else(|error| exit_with_file_error(error, file_path, Format::Text));
+
+    let function = match compiler::Compiler::compile(file_path, &source, &mut vm) {
+        Ok(function) => function,
+        Err(errors) => {
+            report_compile_errors(&errors, file_path, &source);
+            exit(65);
+        }
+    };
+    // SAFETY: The function has just been compiled, and nothing is allocated until it is serialized
+    let bytes = bytecode::serialize(file_path, unsafe { &*function });
+
+    let output = output.unwrap_or_else(|| {
+        Path::new(file_path)
+            .with_extension("loxc")
+            .to_string_lossy()
+            .into_owned()
+    });
+    if let Err(err) = fs::write(&output, bytes) {
+        eprintln!("Could not write file {output}: {err}");
+        exit(74);
     }
+
+    exit(0)
+}
+
+/// A problem with the file as a whole, which stops the command before any of its source is looked at
+struct FileError {
+    code: &'static str,
+    /// For the JSON output, which already names the file
+    message: String,
+    /// For the text output
+    text: String,
+    /// Exit status of the process
+    status: i32,
+}
+
+impl FileError {
+    fn to_json(&self, file_path: &str) -> String {
+        let diagnostic = Diagnostic::file_error(self.code, self.message.clone());
+        diagnostic::to_json(&[diagnostic], file_path)
+    }
+}
+
+fn read_file(file_path: &str) -> Result<Vec<u8>, FileError> {
+    fs::read(file_path).map_err(|err| FileError {
+        code: "io",
+        message: format!("Could not open file: {err}"),
+        text: format!("Could not open file {file_path}: {err}"),
+        status: 74,
+    })
+}
+
+fn read_source(file_path: &str, bytes: Vec<u8>) -> Result<String, FileError> {
+    String::from_utf8(bytes).map_err(|_| FileError {
+        code: "io",
+        message: "The file is not valid UTF-8.".to_owned(),
+        text: format!("Could not read file {file_path}: it is not valid UTF-8"),
+        status: 74,
+    })
+}
+
+/// Reports the error to stderr like the errors of the script
+fn exit_with_file_error(error: FileError, file_path: &str, format: Format) -> ! {
+    match format {
+        Format::Text => eprintln!("{}", error.text),
+        Format::Json => eprintln!("{}", error.to_json(file_path)),
+    }
+    exit(error.status)
+}
+
+fn interpret(vm: &mut VM, file_name: &str, source: &str, format: Format) -> InterpretResult {
+    let result = vm.interpret(file_name, source);
+    report_result(&result, file_name, source, format);
+    result
+}
+
+/// Prints the errors of the script to stderr, the JSON array is printed even if there are none
+fn report_result(result: &InterpretResult, file_name: &str, source: &str, format: Format) {
+    match format {
+        Format::Text => match result {
+            InterpretResult::Ok => {}
+            InterpretResult::CompileError(errors) => {
+                report_compile_errors(errors, file_name, source)
+            }
+            InterpretResult::RuntimeError(error) => report_runtime_error(error),
+        },
+        Format::Json => {
+            let (file_name, diagnostics) = match result {
+                InterpretResult::Ok => (file_name, Vec::new()),
+                InterpretResult::CompileError(errors) => (
+                    file_name,
+                    errors.iter().map(CompileError::diagnostic).collect(),
+                ),
+                InterpretResult::RuntimeError(error) => {
+                    (error.file_name.as_str(), vec![error.diagnostic()])
+                }
+            };
+            eprintln!("{}", diagnostic::to_json(&diagnostics, file_name));
+        }
+    }
+}
+
+fn report_compile_errors(errors: &[CompileError], file_name: &str, source: &str) {
+    let diagnostics: Vec<_> = errors.iter().map(CompileError::diagnostic).collect();
+    diagnostic::emit(&diagnostics, file_name, source);
 }
 
-fn interpret(vm: &mut VM, source: &str) -> vm::InterpretResult {
-    vm.interpret(source)
+fn report_runtime_error(error: &RuntimeError) {
+    eprintln!("{error}");
 }
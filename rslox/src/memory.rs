@@ -0,0 +1,256 @@
+#[cfg(feature = "debug_log_gc")]
+use crate::{object::print_object, value::print_value};
+use crate::{
+    object::{
+        Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjList, ObjType,
+        ObjUpvalue, as_obj, free_object, object_size,
+    },
+    table::Table,
+    value::Value,
+    vm::VM,
+};
+
+/// After a collection, the next one happens when the heap has grown by this factor
+const GC_HEAP_GROW_FACTOR: usize = 2;
+/// Collections are not triggered below this heap size, so small heaps are not collected all the time
+pub const GC_MIN_THRESHOLD: usize = 1024 * 1024;
+
+impl VM {
+    /// Moves the object to the heap and registers it in the VM's object list.
+    /// May trigger a garbage collection before allocating, so every object the new one refers to must be reachable from the roots.
+    ///
+    /// `T` must be one of the `#[repr(C)]` object types, which start with an `Obj` header
+    pub(crate) fn allocate_object<T>(&mut self, object: T) -> *mut T {
+        #[cfg(feature = "debug_stress_gc")]
+        self.collect_garbage();
+
+        if self.bytes_allocated > self.next_gc {
+            self.collect_garbage();
+        }
+
+        let object = Box::into_raw(Box::new(object));
+
+        let header = object as *mut Obj;
+        // SAFETY: Every object type starts with an Obj header, and we have just allocated it
+        unsafe {
+            (*header).next = self.objects;
+            self.bytes_allocated += object_size(header);
+
+            #[cfg(feature = "debug_log_gc")]
+            println!(
+                "{header:?} allocate {} for {:?}",
+                object_size(header),
+                (*header).typ
+            );
+        }
+        self.objects = header;
+
+        object
+    }
+
+    pub fn collect_garbage(&mut self) {
+        #[cfg(feature = "debug_log_gc")]
+        let before = {
+            println!("-- gc begin");
+            self.bytes_allocated
+        };
+
+        self.mark_roots();
+        self.trace_references();
+        // Interned strings are weak references, they don't keep the strings alive
+        self.strings.remove_white();
+        self.sweep();
+
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(GC_MIN_THRESHOLD);
+
+        #[cfg(feature = "debug_log_gc")]
+        {
+            println!("-- gc end");
+            println!(
+                "   collected {} bytes (from {before} to {}) next at {}",
+                before - self.bytes_allocated,
+                self.bytes_allocated,
+                self.next_gc
+            );
+        }
+    }
+
+    fn mark_roots(&mut self) {
+        let mut slot = self.stack_ptr_range.start;
+        while slot < self.stack_top {
+            // SAFETY: Every slot below the stack top holds a live value
+            unsafe {
+                mark_value(&mut self.gray_stack, *slot);
+                slot = slot.add(1);
+            }
+        }
+
+        for frame in &self.frames {
+            mark_object(&mut self.gray_stack, frame.closure as *mut Obj);
+        }
+
+        let mut upvalue = self.open_upvalues;
+        while !upvalue.is_null() {
+            mark_object(&mut self.gray_stack, upvalue as *mut Obj);
+            // SAFETY: Open upvalues are live objects
+            upvalue = unsafe { (*upvalue).next };
+        }
+
+        mark_table(&mut self.gray_stack, &self.globals);
+        mark_object(&mut self.gray_stack, self.init_string as *mut Obj);
+
+        // Functions that are still being compiled are not reachable from anywhere else yet
+        for &function in &self.compiler_roots {
+            mark_object(&mut self.gray_stack, function as *mut Obj);
+        }
+
+        for &value in &self.temp_roots {
+            mark_value(&mut self.gray_stack, value);
+        }
+    }
+
+    /// Blackens gray objects until there are none left, every object not marked by then is unreachable
+    fn trace_references(&mut self) {
+        while let Some(object) = self.gray_stack.pop() {
+            self.blacken_object(object);
+        }
+    }
+
+    /// Marks every object referenced by an already marked object
+    fn blacken_object(&mut self, object: *mut Obj) {
+        #[cfg(feature = "debug_log_gc")]
+        {
+            print!("{object:?} blacken ");
+            print_object(object);
+            println!();
+        }
+
+        let gray_stack = &mut self.gray_stack;
+        // SAFETY: Only live objects are marked
+        unsafe {
+            match (*object).typ {
+                ObjType::String | ObjType::Native => {}
+                ObjType::Upvalue => mark_value(gray_stack, as_obj::<ObjUpvalue>(object).closed),
+                ObjType::Function => {
+                    let function = as_obj::<ObjFunction>(object);
+                    mark_object(gray_stack, function.name as *mut Obj);
+                    for &constant in &function.chunk.constants {
+                        mark_value(gray_stack, constant);
+                    }
+                }
+                ObjType::Closure => {
+                    let closure = as_obj::<ObjClosure>(object);
+                    mark_object(gray_stack, closure.function as *mut Obj);
+                    for &upvalue in &closure.upvalues {
+                        mark_object(gray_stack, upvalue as *mut Obj);
+                    }
+                }
+                ObjType::Class => {
+                    let class = as_obj::<ObjClass>(object);
+                    mark_object(gray_stack, class.name as *mut Obj);
+                    mark_table(gray_stack, &class.methods);
+                }
+                ObjType::Instance => {
+                    let instance = as_obj::<ObjInstance>(object);
+                    mark_object(gray_stack, instance.class as *mut Obj);
+                    mark_table(gray_stack, &instance.fields);
+                }
+                ObjType::BoundMethod => {
+                    let bound_method = as_obj::<ObjBoundMethod>(object);
+                    mark_value(gray_stack, bound_method.receiver);
+                    mark_object(gray_stack, bound_method.method as *mut Obj);
+                }
+                ObjType::List => {
+                    for &item in &as_obj::<ObjList>(object).items {
+                        mark_value(gray_stack, item);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Frees every unmarked object, and clears the marks of the rest for the next collection
+    fn sweep(&mut self) {
+        let mut previous: *mut Obj = std::ptr::null_mut();
+        let mut object = self.objects;
+
+        // SAFETY: Every object in the list is live, until we free it here
+        unsafe {
+            while !object.is_null() {
+                if (*object).is_marked {
+                    (*object).is_marked = false;
+                    previous = object;
+                    object = (*object).next;
+                    continue;
+                }
+
+                let unreached = object;
+                object = (*object).next;
+                if previous.is_null() {
+                    self.objects = object;
+                } else {
+                    (*previous).next = object;
+                }
+
+                self.bytes_allocated -= object_size(unreached);
+                #[cfg(feature = "debug_log_gc")]
+                println!("{unreached:?} free type {:?}", (*unreached).typ);
+                free_object(unreached);
+            }
+        }
+    }
+
+    pub(crate) fn free_objects(&mut self) {
+        let mut object = self.objects;
+        while !object.is_null() {
+            // SAFETY: Every object in the list was allocated by allocate_object and is only freed here
+            unsafe {
+                let next = (*object).next;
+                free_object(object);
+                object = next;
+            }
+        }
+
+        self.objects = std::ptr::null_mut();
+        self.bytes_allocated = 0;
+    }
+}
+
+fn mark_table(gray_stack: &mut Vec<*mut Obj>, table: &Table) {
+    for (key, value) in table.iter() {
+        mark_object(gray_stack, key as *mut Obj);
+        mark_value(gray_stack, value);
+    }
+}
+
+fn mark_value(gray_stack: &mut Vec<*mut Obj>, value: Value) {
+    if let Value::Obj(object) = value {
+        mark_object(gray_stack, object);
+    }
+}
+
+/// Marks the object as reachable, and queues it to mark the objects it references
+fn mark_object(gray_stack: &mut Vec<*mut Obj>, object: *mut Obj) {
+    if object.is_null() {
+        return;
+    }
+
+    // SAFETY: Only live objects are reachable from the roots
+    unsafe {
+        // Objects can be referenced multiple times, and there can be cycles
+        if (*object).is_marked {
+            return;
+        }
+
+        #[cfg(feature = "debug_log_gc")]
+        {
+            print!("{object:?} mark ");
+            print_value(Value::Obj(object));
+            println!();
+        }
+
+        (*object).is_marked = true;
+    }
+
+    gray_stack.push(object);
+}
@@ -0,0 +1,178 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    object::NativeFn,
+    value::{FromLox, IntoLox, Value, sealed::Token},
+    vm::VM,
+};
+
+/// Seconds elapsed since the UNIX epoch
+pub fn clock() -> Result<f64, String> {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|err| format!("System clock is before the UNIX epoch: {err}"))?;
+
+    Ok(elapsed.as_secs_f64())
+}
+
+/// Return values of host functions, returning an error message raises a runtime error
+pub trait NativeResult {
+    /// Like `IntoLox::into_lox`, the objects in the value are not rooted
+    fn into_result(self, vm: &mut VM, token: Token) -> Result<Value, String>;
+}
+
+impl<T: IntoLox> NativeResult for T {
+    fn into_result(self, vm: &mut VM, token: Token) -> Result<Value, String> {
+        Ok(self.into_lox(vm, token))
+    }
+}
+
+impl<T: IntoLox> NativeResult for Result<T, String> {
+    fn into_result(self, vm: &mut VM, token: Token) -> Result<Value, String> {
+        self.map(|value| value.into_lox(vm, token))
+    }
+}
+
+/// Rust closures that can be registered as host functions with `VM::register`.
+/// `Args` is the tuple of the closure's parameter types, it only tells the implementations apart.
+/// The closures only see converted values, never the VM, so they cannot run Lox code while the VM is running.
+pub trait IntoNative<Args> {
+    const ARITY: usize;
+
+    /// Wraps the closure, so it converts the arguments, and raises a runtime error if one has the wrong type
+    fn into_native(self, token: Token) -> NativeFn;
+}
+
+fn argument<T: FromLox>(position: usize, value: Value) -> Result<T, String> {
+    // The arguments are rooted on the stack while the native runs
+    T::from_lox(value, Token(()))
+        .ok_or_else(|| format!("Argument {position} must be {}.", T::type_name()))
+}
+
+/// Implements `IntoNative` for closures with the given parameters, written as `position name: Type`
+macro_rules! impl_into_native {
+    ($($position:literal $arg:ident: $typ:ident),*) => {
+        impl<Func, R, $($typ),*> IntoNative<($($typ,)*)> for Func
+        where
+            Func: Fn($($typ),*) -> R + 'static,
+            R: NativeResult,
+            $($typ: FromLox,)*
+        {
+            const ARITY: usize = <[&str]>::len(&[$(stringify!($arg)),*]);
+
+            fn into_native(self, _: Token) -> NativeFn {
+                Box::new(move |vm, args| {
+                    let &[$($arg),*] = args else {
+                        unreachable!("the VM to check the arity before calling natives");
+                    };
+                    // The VM pushes the result right after the native returns
+                    self($(argument::<$typ>($position, $arg)?),*).into_result(vm, Token(()))
+                })
+            }
+        }
+    };
+}
+
+impl_into_native!();
+impl_into_native!(1 a: A);
+impl_into_native!(1 a: A, 2 b: B);
+impl_into_native!(1 a: A, 2 b: B, 3 c: C);
+impl_into_native!(1 a: A, 2 b: B, 3 c: C, 4 d: D);
+impl_into_native!(1 a: A, 2 b: B, 3 c: C, 4 d: D, 5 e: E);
+impl_into_native!(1 a: A, 2 b: B, 3 c: C, 4 d: D, 5 e: E, 6 f: F);
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use crate::{Interpreter, LoxError, value::Value};
+
+    fn runtime_error(interpreter: &mut Interpreter, source: &str) -> String {
+        match interpreter.eval::<Value>(source) {
+            Err(LoxError::Runtime(error)) => error.message,
+            _ => panic!("expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn registered_functions_convert_arguments_and_results() {
+        let mut interpreter = Interpreter::new();
+        interpreter.vm().register("add", |a: f64, b: f64| a + b);
+        interpreter.vm().register("greet", |name: Option<String>| {
+            format!("hello {}", name.as_deref().unwrap_or("world"))
+        });
+
+        assert_eq!(interpreter.eval::<f64>("add(1, 2)").unwrap(), 3.0);
+        assert_eq!(
+            interpreter.eval::<String>("greet(nil)").unwrap(),
+            "hello world"
+        );
+        assert_eq!(
+            interpreter.eval::<String>("greet(\"lox\")").unwrap(),
+            "hello lox"
+        );
+    }
+
+    #[test]
+    fn registered_functions_check_arguments() {
+        let mut interpreter = Interpreter::new();
+        interpreter.vm().register("add", |a: f64, b: f64| a + b);
+        interpreter.vm().register("half", |n: i64| n / 2);
+
+        assert_eq!(
+            runtime_error(&mut interpreter, "add(1)"),
+            "Expected 2 arguments but got 1."
+        );
+        assert_eq!(
+            runtime_error(&mut interpreter, "add(1, \"2\")"),
+            "Argument 2 must be a number."
+        );
+        assert_eq!(
+            runtime_error(&mut interpreter, "half(1.5)"),
+            "Argument 1 must be an integer."
+        );
+    }
+
+    #[test]
+    fn err_results_raise_runtime_errors() {
+        let mut interpreter = Interpreter::new();
+        interpreter.vm().register("half", |n: i64| {
+            if n % 2 == 0 {
+                Ok(n / 2)
+            } else {
+                Err(format!("{n} is odd."))
+            }
+        });
+
+        assert_eq!(interpreter.eval::<i64>("half(4)").unwrap(), 2);
+        assert_eq!(runtime_error(&mut interpreter, "half(3)"), "3 is odd.");
+    }
+
+    #[test]
+    fn raw_natives_can_only_return_their_arguments() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .vm()
+            .define_native("first", 2, |args| Ok(args[0]));
+        // Returns the argument of the previous call, whose object may have been freed since
+        let kept = Cell::new(Value::Nil);
+        interpreter
+            .vm()
+            .define_native("keep", 1, move |args| Ok(kept.replace(args[0])));
+
+        assert_eq!(interpreter.eval::<f64>("first(1, 2)").unwrap(), 1.0);
+        assert_eq!(interpreter.eval::<String>("first(\"a\", 2)").unwrap(), "a");
+        assert_eq!(
+            runtime_error(&mut interpreter, "first()"),
+            "Expected 2 arguments but got 0."
+        );
+        assert_eq!(
+            interpreter.eval::<Value>("keep(\"a\")").unwrap(),
+            Value::Nil
+        );
+        assert_eq!(
+            runtime_error(&mut interpreter, "keep(1)"),
+            "Native functions can only return objects they were passed."
+        );
+    }
+}
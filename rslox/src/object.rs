@@ -0,0 +1,349 @@
+use std::ptr;
+
+use crate::{
+    chunk::Chunk,
+    table::Table,
+    value::{Value, print_value},
+    vm::VM,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjType {
+    String,
+    Function,
+    Native,
+    Closure,
+    Upvalue,
+    Class,
+    Instance,
+    BoundMethod,
+    List,
+}
+
+/// Header shared by every heap allocated object.
+///
+/// Every concrete object type is `#[repr(C)]` and starts with an `Obj`, so a pointer to any of them
+/// can be cast to `*mut Obj` and back, based on `typ`.
+#[repr(C)]
+pub struct Obj {
+    pub typ: ObjType,
+    /// Set while the garbage collector traces the reachable objects
+    pub is_marked: bool,
+    /// Intrusive linked list of every object allocated by the VM, used to free them
+    pub next: *mut Obj,
+}
+
+#[repr(C)]
+pub struct ObjString {
+    pub obj: Obj,
+    pub hash: u32,
+    pub chars: String,
+}
+
+#[repr(C)]
+pub struct ObjFunction {
+    pub obj: Obj,
+    pub arity: usize,
+    pub upvalue_count: usize,
+    /// Most stack slots a call uses, including the callee and its arguments, set once its code is verified
+    pub max_stack: usize,
+    pub chunk: Chunk,
+    /// Null for the top-level script
+    pub name: *mut ObjString,
+}
+
+/// Host function callable from Lox. The arguments are checked against the arity before calling it,
+/// and returning an error message raises a runtime error.
+/// The arguments stay on the stack during the call, so they are kept alive if the function allocates.
+pub type NativeFn = Box<dyn Fn(&mut VM, &[Value]) -> Result<Value, String>>;
+
+#[repr(C)]
+pub struct ObjNative {
+    pub obj: Obj,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+/// Runtime representation of a function declaration, with the variables it has captured
+#[repr(C)]
+pub struct ObjClosure {
+    pub obj: Obj,
+    pub function: *mut ObjFunction,
+    pub upvalues: Vec<*mut ObjUpvalue>,
+}
+
+/// A variable captured by a closure
+#[repr(C)]
+pub struct ObjUpvalue {
+    pub obj: Obj,
+    /// Points to the variable's stack slot while it is open,
+    /// and to `closed` after the variable has left the stack
+    pub location: *mut Value,
+    pub closed: Value,
+    /// Open upvalues form a linked list, sorted by their stack slot, from the top of the stack
+    pub next: *mut ObjUpvalue,
+}
+
+#[repr(C)]
+pub struct ObjClass {
+    pub obj: Obj,
+    pub name: *mut ObjString,
+    /// Method names to their closures
+    pub methods: Table,
+}
+
+#[repr(C)]
+pub struct ObjInstance {
+    pub obj: Obj,
+    pub class: *mut ObjClass,
+    pub fields: Table,
+}
+
+/// A method accessed on an instance, which remembers the instance it was accessed on, to use as `this`
+#[repr(C)]
+pub struct ObjBoundMethod {
+    pub obj: Obj,
+    pub receiver: Value,
+    pub method: *mut ObjClosure,
+}
+
+/// A list of values created by the host, Lox code can only pass them around and print them
+#[repr(C)]
+pub struct ObjList {
+    pub obj: Obj,
+    pub items: Vec<Value>,
+}
+
+impl Obj {
+    fn new(typ: ObjType) -> Obj {
+        Obj {
+            typ,
+            is_marked: false,
+            next: ptr::null_mut(),
+        }
+    }
+}
+
+impl ObjString {
+    pub fn new(chars: String, hash: u32) -> ObjString {
+        ObjString {
+            obj: Obj::new(ObjType::String),
+            hash,
+            chars,
+        }
+    }
+}
+
+impl ObjFunction {
+    pub fn new() -> ObjFunction {
+        ObjFunction {
+            obj: Obj::new(ObjType::Function),
+            arity: 0,
+            upvalue_count: 0,
+            max_stack: 0,
+            chunk: Chunk::new(),
+            name: ptr::null_mut(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        if self.name.is_null() {
+            "script"
+        } else {
+            // SAFETY: The name is a live string, if it is not null
+            unsafe { &(*self.name).chars }
+        }
+    }
+}
+
+impl ObjNative {
+    pub fn new(arity: usize, function: NativeFn) -> ObjNative {
+        ObjNative {
+            obj: Obj::new(ObjType::Native),
+            arity,
+            function,
+        }
+    }
+}
+
+impl ObjClosure {
+    pub fn new(function: *mut ObjFunction) -> ObjClosure {
+        ObjClosure {
+            obj: Obj::new(ObjType::Closure),
+            function,
+            // SAFETY: Closures are created from live functions
+            upvalues: Vec::with_capacity(unsafe { (*function).upvalue_count }),
+        }
+    }
+
+    pub fn function(&self) -> &ObjFunction {
+        // SAFETY: A closure keeps its function alive
+        unsafe { &*self.function }
+    }
+}
+
+impl ObjUpvalue {
+    pub fn new(slot: *mut Value) -> ObjUpvalue {
+        ObjUpvalue {
+            obj: Obj::new(ObjType::Upvalue),
+            location: slot,
+            closed: Value::Nil,
+            next: ptr::null_mut(),
+        }
+    }
+}
+
+impl ObjClass {
+    pub fn new(name: *mut ObjString) -> ObjClass {
+        ObjClass {
+            obj: Obj::new(ObjType::Class),
+            name,
+            methods: Table::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        // SAFETY: A class keeps its name alive
+        unsafe { &(*self.name).chars }
+    }
+}
+
+impl ObjInstance {
+    pub fn new(class: *mut ObjClass) -> ObjInstance {
+        ObjInstance {
+            obj: Obj::new(ObjType::Instance),
+            class,
+            fields: Table::new(),
+        }
+    }
+
+    pub fn class(&self) -> &ObjClass {
+        // SAFETY: An instance keeps its class alive
+        unsafe { &*self.class }
+    }
+}
+
+impl ObjBoundMethod {
+    pub fn new(receiver: Value, method: *mut ObjClosure) -> ObjBoundMethod {
+        ObjBoundMethod {
+            obj: Obj::new(ObjType::BoundMethod),
+            receiver,
+            method,
+        }
+    }
+
+    pub fn method(&self) -> &ObjClosure {
+        // SAFETY: A bound method keeps its method alive
+        unsafe { &*self.method }
+    }
+}
+
+impl ObjList {
+    pub fn new(items: Vec<Value>) -> ObjList {
+        ObjList {
+            obj: Obj::new(ObjType::List),
+            items,
+        }
+    }
+}
+
+/// FNV-1a hash
+pub fn hash_string(chars: &str) -> u32 {
+    let mut hash: u32 = 2166136261;
+    for byte in chars.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(16777619);
+    }
+    hash
+}
+
+/// Reinterprets an object header as the concrete object type it belongs to
+///
+/// # Safety
+/// `obj` must point to a live object whose `typ` corresponds to `T`
+pub unsafe fn as_obj<'a, T>(obj: *mut Obj) -> &'a T {
+    unsafe { &*(obj as *const T) }
+}
+
+/// Number of bytes owned by the object, used to decide when to collect garbage.
+/// It must not change during the object's lifetime, so growable parts, like a function's chunk or a list's items, are not counted.
+///
+/// # Safety
+/// `obj` must point to a live object
+pub unsafe fn object_size(obj: *mut Obj) -> usize {
+    use std::mem::size_of;
+
+    unsafe {
+        match (*obj).typ {
+            ObjType::String => size_of::<ObjString>() + as_obj::<ObjString>(obj).chars.capacity(),
+            ObjType::Function => size_of::<ObjFunction>(),
+            ObjType::Native => size_of::<ObjNative>(),
+            ObjType::Closure => {
+                size_of::<ObjClosure>()
+                    + as_obj::<ObjClosure>(obj).upvalues.capacity() * size_of::<*mut ObjUpvalue>()
+            }
+            ObjType::Upvalue => size_of::<ObjUpvalue>(),
+            ObjType::Class => size_of::<ObjClass>(),
+            ObjType::Instance => size_of::<ObjInstance>(),
+            ObjType::BoundMethod => size_of::<ObjBoundMethod>(),
+            // Lists are filled before they are allocated, and they never grow, so this doesn't change until they are freed
+            ObjType::List => {
+                size_of::<ObjList>() + as_obj::<ObjList>(obj).items.capacity() * size_of::<Value>()
+            }
+        }
+    }
+}
+
+/// # Safety
+/// `obj` must point to a live object allocated with `Box`, that is not referenced anywhere anymore
+pub unsafe fn free_object(obj: *mut Obj) {
+    unsafe {
+        match (*obj).typ {
+            ObjType::String => drop(Box::from_raw(obj as *mut ObjString)),
+            ObjType::Function => drop(Box::from_raw(obj as *mut ObjFunction)),
+            ObjType::Native => drop(Box::from_raw(obj as *mut ObjNative)),
+            ObjType::Closure => drop(Box::from_raw(obj as *mut ObjClosure)),
+            ObjType::Upvalue => drop(Box::from_raw(obj as *mut ObjUpvalue)),
+            ObjType::Class => drop(Box::from_raw(obj as *mut ObjClass)),
+            ObjType::Instance => drop(Box::from_raw(obj as *mut ObjInstance)),
+            ObjType::BoundMethod => drop(Box::from_raw(obj as *mut ObjBoundMethod)),
+            ObjType::List => drop(Box::from_raw(obj as *mut ObjList)),
+        }
+    }
+}
+
+pub fn print_object(obj: *mut Obj) {
+    // SAFETY: Values only ever hold pointers to live objects
+    unsafe {
+        match (*obj).typ {
+            ObjType::String => print!("{}", as_obj::<ObjString>(obj).chars),
+            ObjType::Function => print_function(as_obj(obj)),
+            ObjType::Native => print!("<native fn>"),
+            ObjType::Closure => print_function(as_obj::<ObjClosure>(obj).function()),
+            ObjType::Upvalue => print!("upvalue"),
+            ObjType::Class => print!("{}", as_obj::<ObjClass>(obj).name()),
+            ObjType::Instance => print!("{} instance", as_obj::<ObjInstance>(obj).class().name()),
+            ObjType::BoundMethod => {
+                print_function(as_obj::<ObjBoundMethod>(obj).method().function())
+            }
+            ObjType::List => {
+                print!("[");
+                for (i, &item) in as_obj::<ObjList>(obj).items.iter().enumerate() {
+                    if i > 0 {
+                        print!(", ");
+                    }
+                    print_value(item);
+                }
+                print!("]");
+            }
+        }
+    }
+}
+
+fn print_function(function: &ObjFunction) {
+    if function.name.is_null() {
+        print!("<script>");
+    } else {
+        print!("<fn {}>", function.name());
+    }
+}
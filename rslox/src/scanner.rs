@@ -10,13 +10,45 @@ pub struct Scanner<'a> {
     start: usize,
     current: usize,
     line: usize,
+    column: usize,
+    /// Line and column of the token being scanned
+    start_line: usize,
+    start_column: usize,
+}
+
+/// Byte offsets into the source, `end` is exclusive
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Where something is in the source. Lines and columns start at 1, columns count characters.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Default)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+    pub span: Span,
 }
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub struct Token {
     pub typ: TokenType,
     pub str: String,
+    /// Line and column of the token's first character
     pub line: usize,
+    pub column: usize,
+    pub span: Span,
+}
+
+impl Token {
+    pub fn location(&self) -> Location {
+        Location {
+            line: self.line,
+            column: self.column,
+            span: self.span,
+        }
+    }
 }
 
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
@@ -82,6 +114,9 @@ impl Scanner<'_> {
             start: 0,
             current: 0,
             line: 1,
+            column: 1,
+            start_line: 1,
+            start_column: 1,
         }
     }
 
@@ -89,6 +124,8 @@ impl Scanner<'_> {
         self.skip_whitespace();
 
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.column;
 
         if self.is_at_end() {
             return self.make_token(TokenType::Eof);
@@ -156,6 +193,12 @@ impl Scanner<'_> {
         let c = self.source_iter.next().unwrap();
         self.current_str.push(c);
         self.current += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
         c
     }
 
@@ -179,7 +222,9 @@ impl Scanner<'_> {
         Token {
             typ,
             str,
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
+            span: self.span(),
         }
     }
 
@@ -189,7 +234,16 @@ impl Scanner<'_> {
         Token {
             typ: TokenType::Error,
             str: message.to_owned(),
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
+            span: self.span(),
+        }
+    }
+
+    fn span(&self) -> Span {
+        Span {
+            start: self.start,
+            end: self.current,
         }
     }
 
@@ -198,10 +252,6 @@ impl Scanner<'_> {
             let peek = self.source_iter.peek();
             match peek {
                 None => break,
-                Some('\n') => {
-                    self.line += 1;
-                    self.advance();
-                }
                 Some(c) if c.is_whitespace() => {
                     self.advance();
                 }
@@ -209,9 +259,11 @@ impl Scanner<'_> {
                     let peek_next = self.source_iter.peek_next();
                     if peek_next == Some(&'/') {
                         // If we peek `//` then read until the end of the line, and then continue the whitespace removal loop
-                        while let Some(c) = self.source_iter.next()
-                            && c != '\n'
-                        {}
+                        while let Some(c) = self.source_iter.peek()
+                            && *c != '\n'
+                        {
+                            self.advance();
+                        }
                         continue;
                     } else {
                         break;
@@ -229,10 +281,6 @@ impl Scanner<'_> {
         while let Some(c) = self.source_iter.peek()
             && *c != '"'
         {
-            if *c == '\n' {
-                self.line += 1;
-            }
-
             self.advance();
         }
 
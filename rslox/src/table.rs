@@ -0,0 +1,192 @@
+use std::ptr;
+
+use crate::{object::ObjString, value::Value};
+
+const TABLE_MAX_LOAD: f64 = 0.75;
+
+/// An empty bucket has a null key and a `nil` value,
+/// a tombstone (a deleted entry) has a null key and a `true` value.
+#[derive(Clone, Copy)]
+struct Entry {
+    key: *mut ObjString,
+    value: Value,
+}
+
+impl Entry {
+    const EMPTY: Entry = Entry {
+        key: ptr::null_mut(),
+        value: Value::Nil,
+    };
+
+    const TOMBSTONE: Entry = Entry {
+        key: ptr::null_mut(),
+        value: Value::Bool(true),
+    };
+
+    fn is_tombstone(&self) -> bool {
+        self.key.is_null() && self.value != Value::Nil
+    }
+}
+
+/// Open addressing hash table with linear probing, keyed by interned strings.
+///
+/// Because every string is interned, keys are compared by their pointers.
+pub struct Table {
+    /// Number of live entries plus tombstones
+    count: usize,
+    entries: Vec<Entry>,
+}
+
+impl Table {
+    pub fn new() -> Table {
+        Table {
+            count: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn get(&self, key: *mut ObjString) -> Option<Value> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let entry = &self.entries[find_entry(&self.entries, key)];
+        if entry.key.is_null() {
+            return None;
+        }
+
+        Some(entry.value)
+    }
+
+    /// Returns `true` if a new key was added, and `false` if an existing key's value was overwritten
+    pub fn set(&mut self, key: *mut ObjString, value: Value) -> bool {
+        if (self.count + 1) as f64 > self.entries.len() as f64 * TABLE_MAX_LOAD {
+            let capacity = grow_capacity(self.entries.len());
+            self.adjust_capacity(capacity);
+        }
+
+        let index = find_entry(&self.entries, key);
+        let entry = &mut self.entries[index];
+        let is_new_key = entry.key.is_null();
+        // Reusing a tombstone doesn't change the count, it has already been counted
+        if is_new_key && !entry.is_tombstone() {
+            self.count += 1;
+        }
+
+        entry.key = key;
+        entry.value = value;
+        is_new_key
+    }
+
+    /// Returns `true` if the key was present in the table
+    pub fn delete(&mut self, key: *mut ObjString) -> bool {
+        if self.count == 0 {
+            return false;
+        }
+
+        let index = find_entry(&self.entries, key);
+        let entry = &mut self.entries[index];
+        if entry.key.is_null() {
+            return false;
+        }
+
+        // Leave a tombstone, so probe sequences going through this entry are not broken
+        *entry = Entry::TOMBSTONE;
+        true
+    }
+
+    /// Copies every entry of `from` into this table, overwriting existing keys
+    pub fn add_all(&mut self, from: &Table) {
+        for (key, value) in from.iter() {
+            self.set(key, value);
+        }
+    }
+
+    /// Looks up an interned string by its contents, instead of by its pointer
+    pub fn find_string(&self, chars: &str, hash: u32) -> Option<*mut ObjString> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let capacity = self.entries.len();
+        let mut index = hash as usize & (capacity - 1);
+        loop {
+            let entry = &self.entries[index];
+            if entry.key.is_null() {
+                // Stop if we find an empty non-tombstone entry
+                if !entry.is_tombstone() {
+                    return None;
+                }
+            } else {
+                // SAFETY: Keys in the table are live, interned strings
+                let key = unsafe { &*entry.key };
+                if key.hash == hash && key.chars == chars {
+                    return Some(entry.key);
+                }
+            }
+
+            index = (index + 1) & (capacity - 1);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (*mut ObjString, Value)> {
+        self.entries
+            .iter()
+            .filter(|entry| !entry.key.is_null())
+            .map(|entry| (entry.key, entry.value))
+    }
+
+    /// Deletes every entry whose key has not been marked by the garbage collector
+    pub fn remove_white(&mut self) {
+        for entry in self.entries.iter_mut() {
+            // SAFETY: Keys in the table are live until the sweep phase, which comes after this
+            if !entry.key.is_null() && unsafe { !(*entry.key).obj.is_marked } {
+                *entry = Entry::TOMBSTONE;
+            }
+        }
+    }
+
+    fn adjust_capacity(&mut self, capacity: usize) {
+        let mut entries = vec![Entry::EMPTY; capacity];
+
+        // Tombstones are not copied over, so we need to recount the entries
+        self.count = 0;
+        for entry in self.entries.iter().filter(|entry| !entry.key.is_null()) {
+            let index = find_entry(&entries, entry.key);
+            entries[index] = *entry;
+            self.count += 1;
+        }
+
+        self.entries = entries;
+    }
+}
+
+/// Returns the index of the entry for `key`, or the index of the bucket where it should be inserted.
+///
+/// `entries` must not be empty, and must have a power of two length.
+fn find_entry(entries: &[Entry], key: *mut ObjString) -> usize {
+    let capacity = entries.len();
+    // SAFETY: Keys passed to the table are live, interned strings
+    let mut index = unsafe { (*key).hash } as usize & (capacity - 1);
+    let mut tombstone = None;
+
+    loop {
+        let entry = &entries[index];
+        if entry.key.is_null() {
+            if !entry.is_tombstone() {
+                // Empty entry, reuse a tombstone we have passed if there was one
+                return tombstone.unwrap_or(index);
+            } else if tombstone.is_none() {
+                tombstone = Some(index);
+            }
+        } else if entry.key == key {
+            return index;
+        }
+
+        index = (index + 1) & (capacity - 1);
+    }
+}
+
+const fn grow_capacity(capacity: usize) -> usize {
+    if capacity < 8 { 8 } else { capacity * 2 }
+}
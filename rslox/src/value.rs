@@ -1,5 +1,332 @@
-pub type Value = f64;
+use crate::{
+    object::{Obj, ObjClass, ObjInstance, ObjList, ObjString, ObjType, as_obj, print_object},
+    vm::VM,
+};
 
-pub fn print_value(value: Value) {
-    print!("{value}");
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Nil,
+    Number(f64),
+    /// Objects are owned by the VM, only it can create these values, so they always point at live objects
+    #[non_exhaustive]
+    Obj(*mut Obj),
+}
+
+impl Value {
+    /// `nil` and `false` are falsey, every other value is truthy
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    pub(crate) fn is_obj_type(&self, typ: ObjType) -> bool {
+        // SAFETY: Values only ever hold pointers to live objects
+        matches!(self, Value::Obj(obj) if unsafe { (**obj).typ } == typ)
+    }
+
+    pub(crate) fn as_string_ptr(self) -> Option<*mut ObjString> {
+        match self {
+            Value::Obj(obj) if self.is_obj_type(ObjType::String) => Some(obj as *mut ObjString),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_class_ptr(self) -> Option<*mut ObjClass> {
+        match self {
+            Value::Obj(obj) if self.is_obj_type(ObjType::Class) => Some(obj as *mut ObjClass),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_instance_ptr(self) -> Option<*mut ObjInstance> {
+        match self {
+            Value::Obj(obj) if self.is_obj_type(ObjType::Instance) => Some(obj as *mut ObjInstance),
+            _ => None,
+        }
+    }
+
+    // TODO(safety): The returned reference is only valid while the VM owning the object is alive
+    pub(crate) fn as_string<'a>(self) -> Option<&'a ObjString> {
+        match self {
+            // SAFETY: We have checked the type of the object
+            Value::Obj(obj) if self.is_obj_type(ObjType::String) => Some(unsafe { as_obj(obj) }),
+            _ => None,
+        }
+    }
+
+    // TODO(safety): The returned reference is only valid while the VM owning the object is alive
+    pub(crate) fn as_list<'a>(self) -> Option<&'a ObjList> {
+        match self {
+            // SAFETY: We have checked the type of the object
+            Value::Obj(obj) if self.is_obj_type(ObjType::List) => Some(unsafe { as_obj(obj) }),
+            _ => None,
+        }
+    }
+}
+
+//------Conversions between Rust and Lox values------
+
+pub(crate) mod sealed {
+    /// Only this crate can create it, so only it can call or implement the conversions taking it.
+    /// Converting to Lox allocates objects which nothing roots, and converting from Lox reads objects
+    /// which must still be alive, so the VM does both right where it can keep the objects rooted.
+    #[derive(Clone, Copy)]
+    pub struct Token(pub(crate) ());
+}
+
+use sealed::Token;
+
+/// Rust values that can be passed to Lox, objects are allocated in the VM.
+/// The VM converts the results of host functions with it, only this crate can implement it.
+pub trait IntoLox {
+    /// The objects in the value are not rooted, the caller must root them before allocating again
+    fn into_lox(self, vm: &mut VM, token: Token) -> Value;
+}
+
+/// Rust values that can be created from Lox values, for the arguments of host functions and the results of `eval`.
+/// Only this crate can implement it.
+pub trait FromLox: Sized {
+    /// `None` if the value is not of this type, the objects in the value must be rooted
+    fn from_lox(value: Value, token: Token) -> Option<Self>;
+
+    /// Description of the accepted values in error messages, e.g. "a number"
+    fn type_name() -> String;
+}
+
+impl IntoLox for Value {
+    fn into_lox(self, _vm: &mut VM, _: Token) -> Value {
+        self
+    }
+}
+
+impl FromLox for Value {
+    /// Objects are owned by the VM, so they must be converted to a Rust type like `String` instead
+    fn from_lox(value: Value, _: Token) -> Option<Self> {
+        match value {
+            Value::Obj(_) => None,
+            _ => Some(value),
+        }
+    }
+
+    fn type_name() -> String {
+        "a boolean, a number or nil".to_owned()
+    }
+}
+
+impl IntoLox for f64 {
+    fn into_lox(self, _vm: &mut VM, _: Token) -> Value {
+        Value::Number(self)
+    }
+}
+
+impl FromLox for f64 {
+    fn from_lox(value: Value, _: Token) -> Option<Self> {
+        match value {
+            Value::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    fn type_name() -> String {
+        "a number".to_owned()
+    }
+}
+
+impl IntoLox for i64 {
+    /// Lox only has floating point numbers, large integers lose precision
+    fn into_lox(self, _vm: &mut VM, _: Token) -> Value {
+        Value::Number(self as f64)
+    }
+}
+
+impl FromLox for i64 {
+    fn from_lox(value: Value, _: Token) -> Option<Self> {
+        match value {
+            // `i64::MAX as f64` is rounded up to 2^63, which is out of range
+            Value::Number(n) if n.fract() == 0.0 && n >= i64::MIN as f64 && n < i64::MAX as f64 => {
+                Some(n as i64)
+            }
+            _ => None,
+        }
+    }
+
+    fn type_name() -> String {
+        "an integer".to_owned()
+    }
+}
+
+impl IntoLox for bool {
+    fn into_lox(self, _vm: &mut VM, _: Token) -> Value {
+        Value::Bool(self)
+    }
+}
+
+impl FromLox for bool {
+    /// Only accepts booleans, not every truthy or falsey value
+    fn from_lox(value: Value, _: Token) -> Option<Self> {
+        match value {
+            Value::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    fn type_name() -> String {
+        "a boolean".to_owned()
+    }
+}
+
+impl IntoLox for () {
+    fn into_lox(self, _vm: &mut VM, _: Token) -> Value {
+        Value::Nil
+    }
+}
+
+impl FromLox for () {
+    fn from_lox(value: Value, _: Token) -> Option<Self> {
+        match value {
+            Value::Nil => Some(()),
+            _ => None,
+        }
+    }
+
+    fn type_name() -> String {
+        "nil".to_owned()
+    }
+}
+
+impl IntoLox for String {
+    fn into_lox(self, vm: &mut VM, _: Token) -> Value {
+        Value::Obj(vm.take_string(self) as *mut Obj)
+    }
+}
+
+impl IntoLox for &str {
+    fn into_lox(self, vm: &mut VM, _: Token) -> Value {
+        Value::Obj(vm.copy_string(self) as *mut Obj)
+    }
+}
+
+impl FromLox for String {
+    fn from_lox(value: Value, _: Token) -> Option<Self> {
+        value.as_string().map(|string| string.chars.clone())
+    }
+
+    fn type_name() -> String {
+        "a string".to_owned()
+    }
+}
+
+impl<T: IntoLox> IntoLox for Option<T> {
+    fn into_lox(self, vm: &mut VM, token: Token) -> Value {
+        match self {
+            Some(value) => value.into_lox(vm, token),
+            None => Value::Nil,
+        }
+    }
+}
+
+impl<T: FromLox> FromLox for Option<T> {
+    fn from_lox(value: Value, token: Token) -> Option<Self> {
+        match value {
+            Value::Nil => Some(None),
+            _ => T::from_lox(value, token).map(Some),
+        }
+    }
+
+    fn type_name() -> String {
+        format!("{} or nil", T::type_name())
+    }
+}
+
+impl<T: IntoLox> IntoLox for Vec<T> {
+    fn into_lox(self, vm: &mut VM, token: Token) -> Value {
+        // The converted items are rooted, so they are reachable if converting the next one, or allocating the list,
+        // triggers a collection. They are not kept on the stack, which only has room for what the running functions use.
+        let start = vm.temp_roots.len();
+        for item in self {
+            let item = item.into_lox(vm, token);
+            vm.temp_roots.push(item);
+        }
+
+        let items = vm.temp_roots[start..].to_vec();
+        let list = vm.new_list(items);
+        vm.temp_roots.truncate(start);
+        Value::Obj(list as *mut Obj)
+    }
+}
+
+impl<T: FromLox> FromLox for Vec<T> {
+    fn from_lox(value: Value, token: Token) -> Option<Self> {
+        let list = value.as_list()?;
+        list.items
+            .iter()
+            .map(|&item| T::from_lox(item, token))
+            .collect()
+    }
+
+    fn type_name() -> String {
+        format!("a list whose items are each {}", T::type_name())
+    }
+}
+
+pub fn values_equal(a: Value, b: Value) -> bool {
+    match (a, b) {
+        (Value::Bool(a), Value::Bool(b)) => a == b,
+        (Value::Nil, Value::Nil) => true,
+        (Value::Number(a), Value::Number(b)) => a == b,
+        // Strings are interned, so they can be compared by their pointers too
+        (Value::Obj(a), Value::Obj(b)) => a == b,
+        _ => false,
+    }
+}
+
+pub(crate) fn print_value(value: Value) {
+    match value {
+        Value::Bool(b) => print!("{b}"),
+        Value::Nil => print!("nil"),
+        Value::Number(n) => print!("{n}"),
+        Value::Obj(obj) => print_object(obj),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Converts the value to Lox and back, nothing is allocated in between, so its objects are still alive
+    fn round_trip<T: IntoLox + FromLox>(value: T) -> Option<T> {
+        let mut vm = VM::new();
+        let value = value.into_lox(&mut vm, Token(()));
+        T::from_lox(value, Token(()))
+    }
+
+    #[test]
+    fn options_round_trip() {
+        assert_eq!(round_trip(Some(1.5)), Some(Some(1.5)));
+        assert_eq!(round_trip(None::<f64>), Some(None));
+        assert_eq!(
+            round_trip(Some("lox".to_owned())),
+            Some(Some("lox".to_owned()))
+        );
+    }
+
+    #[test]
+    fn vecs_round_trip() {
+        let strings = vec![Some("a".to_owned()), None, Some("b".to_owned())];
+        assert_eq!(round_trip(strings.clone()), Some(strings));
+
+        let nested = vec![vec![1, 2], vec![], vec![3]];
+        assert_eq!(round_trip(nested.clone()), Some(nested));
+    }
+
+    #[test]
+    fn wrong_types_are_rejected() {
+        let mut vm = VM::new();
+        let list = vec![1.0, 2.0].into_lox(&mut vm, Token(()));
+
+        assert_eq!(f64::from_lox(Value::Bool(true), Token(())), None);
+        assert_eq!(i64::from_lox(Value::Number(0.5), Token(())), None);
+        assert_eq!(Vec::<String>::from_lox(list, Token(())), None);
+        assert_eq!(Value::from_lox(list, Token(())), None);
+    }
 }
@@ -0,0 +1,729 @@
+use std::fmt::{self, Display};
+
+use crate::{
+    chunk::{self, Chunk, OpCode},
+    compiler::MAX_UPVALUES,
+    object::{ObjFunction, ObjType, as_obj},
+    value::Value,
+    vm::STACK_MAX,
+};
+
+#[derive(Debug)]
+pub struct VerifyError {
+    /// Name of the function containing the bad instruction
+    pub function: String,
+    pub offset: usize,
+    pub kind: VerifyErrorKind,
+}
+
+#[derive(Debug)]
+pub enum VerifyErrorKind {
+    EmptyCode,
+    /// The location table doesn't start at offset 0, or its offsets are not increasing
+    InvalidLocationTable,
+    InvalidOpcode(u8),
+    TruncatedOperand,
+    ConstantOutOfRange(usize),
+    /// The constant is not of the object type the instruction needs
+    WrongConstantType {
+        index: usize,
+        expected: ObjType,
+    },
+    InvalidJumpTarget(isize),
+    /// Execution can reach the end of the code without returning
+    FallsOffEnd,
+    StackUnderflow,
+    /// A call would need more slots than the whole stack has.
+    /// The stack is shared by every frame, so the VM also checks the space left on it before each call.
+    StackOverflow,
+    /// Two paths reach the same instruction with different stack depths
+    InconsistentStackDepth {
+        expected: usize,
+        found: usize,
+    },
+    LocalOutOfRange(usize),
+    UpvalueOutOfRange(usize),
+    /// More upvalues than a closure can capture, or any for the top-level script
+    InvalidUpvalueCount(usize),
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid bytecode in {} at offset {:04}: ",
+            self.function, self.offset
+        )?;
+
+        match &self.kind {
+            VerifyErrorKind::EmptyCode => write!(f, "the function has no code."),
+            VerifyErrorKind::InvalidLocationTable => write!(f, "invalid location table."),
+            VerifyErrorKind::InvalidOpcode(byte) => write!(f, "unknown opcode {byte}."),
+            VerifyErrorKind::TruncatedOperand => {
+                write!(f, "operand goes past the end of the code.")
+            }
+            VerifyErrorKind::ConstantOutOfRange(index) => {
+                write!(f, "constant {index} is out of range.")
+            }
+            VerifyErrorKind::WrongConstantType { index, expected } => {
+                write!(f, "constant {index} is not a {expected:?}.")
+            }
+            VerifyErrorKind::InvalidJumpTarget(target) => {
+                write!(f, "jump to {target:04} is not an instruction.")
+            }
+            VerifyErrorKind::FallsOffEnd => write!(f, "execution falls off the end of the code."),
+            VerifyErrorKind::StackUnderflow => write!(f, "stack underflow."),
+            VerifyErrorKind::StackOverflow => write!(f, "stack overflow."),
+            VerifyErrorKind::InconsistentStackDepth { expected, found } => write!(
+                f,
+                "stack depth is {found} on one path, but {expected} on another."
+            ),
+            VerifyErrorKind::LocalOutOfRange(slot) => {
+                write!(f, "local slot {slot} is out of range.")
+            }
+            VerifyErrorKind::UpvalueOutOfRange(index) => {
+                write!(f, "upvalue {index} is out of range.")
+            }
+            VerifyErrorKind::InvalidUpvalueCount(count) => {
+                write!(f, "invalid upvalue count {count}.")
+            }
+        }
+    }
+}
+
+/// Checks that the function can be executed without reading or writing out of bounds,
+/// so the VM doesn't have to check it on every instruction.
+/// The functions nested in its constants must have been verified before it.
+///
+/// Returns the most stack slots a call to the function uses, including the callee and its arguments,
+/// which the VM checks against the space left on the stack before calling it.
+pub fn verify(function: &ObjFunction) -> Result<usize, VerifyError> {
+    let verifier = Verifier {
+        function,
+        chunk: &function.chunk,
+    };
+    if function.upvalue_count > MAX_UPVALUES {
+        return Err(verifier.error(
+            0,
+            VerifyErrorKind::InvalidUpvalueCount(function.upvalue_count),
+        ));
+    }
+    let instructions = verifier.decode()?;
+    verifier.check_locations()?;
+    verifier.check_stack(&instructions)
+}
+
+/// Checks what `verify` can't know about the top-level script: it is called without a closure instruction,
+/// so it can't capture anything
+pub fn verify_script(function: &ObjFunction) -> Result<(), VerifyError> {
+    if function.upvalue_count != 0 {
+        return Err(VerifyError {
+            function: function.name().to_owned(),
+            offset: 0,
+            kind: VerifyErrorKind::InvalidUpvalueCount(function.upvalue_count),
+        });
+    }
+
+    Ok(())
+}
+
+/// How an instruction's operands are laid out after its opcode
+enum Operand {
+    None,
+    /// A local slot, an upvalue index or an argument count
+    Byte,
+    /// Index of a constant of any type
+    Constant {
+        is_long: bool,
+    },
+    /// Index of an interned string constant, used as a variable or property name
+    Name {
+        is_long: bool,
+    },
+    Jump {
+        is_forward: bool,
+    },
+    /// A function constant, followed by two bytes for each of its upvalues
+    Closure {
+        is_long: bool,
+    },
+    /// A method name constant, followed by the argument count
+    Invoke {
+        is_long: bool,
+    },
+}
+
+fn operand(opcode: OpCode) -> Operand {
+    use OpCode::*;
+
+    match opcode {
+        Return | Negate | Add | Subtract | Multiply | Divide | Nil | True | False | Not | Equal
+        | Greater | Less | Print | Pop | CloseUpvalue | Inherit => Operand::None,
+        GetLocal | SetLocal | Call | GetUpvalue | SetUpvalue => Operand::Byte,
+        Constant => Operand::Constant { is_long: false },
+        ConstantLong => Operand::Constant { is_long: true },
+        DefineGlobal | GetGlobal | SetGlobal | Class | GetProperty | SetProperty | Method
+        | GetSuper => Operand::Name { is_long: false },
+        DefineGlobalLong | GetGlobalLong | SetGlobalLong | ClassLong | GetPropertyLong
+        | SetPropertyLong | MethodLong | GetSuperLong => Operand::Name { is_long: true },
+        Jump | JumpIfFalse => Operand::Jump { is_forward: true },
+        Loop => Operand::Jump { is_forward: false },
+        Closure => Operand::Closure { is_long: false },
+        ClosureLong => Operand::Closure { is_long: true },
+        Invoke | SuperInvoke => Operand::Invoke { is_long: false },
+        InvokeLong | SuperInvokeLong => Operand::Invoke { is_long: true },
+    }
+}
+
+/// Returns how many values the instruction needs on the stack, and how many it leaves in their place
+fn stack_effect(instruction: &Instruction) -> (usize, usize) {
+    use OpCode::*;
+
+    match instruction.opcode {
+        Constant | ConstantLong | Nil | True | False | GetGlobal | GetGlobalLong | GetLocal
+        | GetUpvalue | Closure | ClosureLong | Class | ClassLong => (0, 1),
+        Jump | Loop => (0, 0),
+        Return | Print | Pop | DefineGlobal | DefineGlobalLong | CloseUpvalue => (1, 0),
+        Negate | Not | SetGlobal | SetGlobalLong | SetLocal | SetUpvalue | JumpIfFalse
+        | GetProperty | GetPropertyLong => (1, 1),
+        Add | Subtract | Multiply | Divide | Equal | Greater | Less | SetProperty
+        | SetPropertyLong | Method | MethodLong | Inherit | GetSuper | GetSuperLong => (2, 1),
+        // The callee or the receiver is below the arguments
+        Call | Invoke | InvokeLong => (instruction.byte + 1, 1),
+        // The superclass is on top of the arguments
+        SuperInvoke | SuperInvokeLong => (instruction.byte + 2, 1),
+    }
+}
+
+/// A decoded instruction, whose operands are in bounds, and whose constant has the right type
+#[derive(Clone, Copy)]
+struct Instruction {
+    opcode: OpCode,
+    /// The byte operand, or the argument count of invocations
+    byte: usize,
+    /// Only used by jumps
+    target: usize,
+    /// Number of upvalue descriptors following a closure instruction
+    upvalue_count: usize,
+    /// Offset of the next instruction
+    next: usize,
+}
+
+struct Verifier<'a> {
+    function: &'a ObjFunction,
+    chunk: &'a Chunk,
+}
+
+impl Verifier<'_> {
+    fn error(&self, offset: usize, kind: VerifyErrorKind) -> VerifyError {
+        VerifyError {
+            function: self.function.name().to_owned(),
+            offset,
+            kind,
+        }
+    }
+
+    /// Decodes every instruction, indexed by their offset
+    fn decode(&self) -> Result<Vec<Option<Instruction>>, VerifyError> {
+        let code = &self.chunk.code;
+        if code.is_empty() {
+            return Err(self.error(0, VerifyErrorKind::EmptyCode));
+        }
+
+        let mut instructions = vec![None; code.len()];
+        let mut offset = 0;
+        while offset < code.len() {
+            let instruction = self.decode_instruction(offset)?;
+            instructions[offset] = Some(instruction);
+            offset = instruction.next;
+        }
+
+        // Jump targets can only be checked once every instruction boundary is known
+        for (offset, instruction) in instructions.iter().enumerate() {
+            let Some(instruction) = instruction else {
+                continue;
+            };
+
+            let is_jump = matches!(
+                instruction.opcode,
+                OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop
+            );
+            if is_jump
+                && instructions
+                    .get(instruction.target)
+                    .is_none_or(Option::is_none)
+            {
+                return Err(self.error(
+                    offset,
+                    VerifyErrorKind::InvalidJumpTarget(instruction.target as isize),
+                ));
+            }
+        }
+
+        Ok(instructions)
+    }
+
+    fn decode_instruction(&self, offset: usize) -> Result<Instruction, VerifyError> {
+        let byte = self.chunk.code[offset];
+        let Ok(opcode) = OpCode::try_from(byte) else {
+            return Err(self.error(offset, VerifyErrorKind::InvalidOpcode(byte)));
+        };
+
+        let mut instruction = Instruction {
+            opcode,
+            byte: 0,
+            target: 0,
+            upvalue_count: 0,
+            next: offset + 1,
+        };
+        let index_len = |is_long| if is_long { 3 } else { 1 };
+
+        match operand(opcode) {
+            Operand::None => {}
+            Operand::Byte => {
+                instruction.byte = self.read(offset, &mut instruction.next, 1)?;
+                let is_upvalue = matches!(opcode, OpCode::GetUpvalue | OpCode::SetUpvalue);
+                if is_upvalue && instruction.byte >= self.function.upvalue_count {
+                    return Err(
+                        self.error(offset, VerifyErrorKind::UpvalueOutOfRange(instruction.byte))
+                    );
+                }
+            }
+            Operand::Constant { is_long } => {
+                let index = self.read(offset, &mut instruction.next, index_len(is_long))?;
+                self.constant(offset, index, None)?;
+            }
+            Operand::Name { is_long } => {
+                let index = self.read(offset, &mut instruction.next, index_len(is_long))?;
+                self.constant(offset, index, Some(ObjType::String))?;
+            }
+            Operand::Jump { is_forward } => {
+                let jump = self.read(offset, &mut instruction.next, 2)?;
+                let target = if is_forward {
+                    instruction.next as isize + jump as isize
+                } else {
+                    instruction.next as isize - jump as isize
+                };
+                if target < 0 {
+                    return Err(self.error(offset, VerifyErrorKind::InvalidJumpTarget(target)));
+                }
+                instruction.target = target as usize;
+            }
+            Operand::Closure { is_long } => {
+                let index = self.read(offset, &mut instruction.next, index_len(is_long))?;
+                let Value::Obj(function) = self.constant(offset, index, Some(ObjType::Function))?
+                else {
+                    unreachable!("constant to be a function");
+                };
+                // SAFETY: We have checked the type of the constant
+                let function = unsafe { as_obj::<ObjFunction>(function) };
+
+                instruction.upvalue_count = function.upvalue_count;
+                for _ in 0..function.upvalue_count {
+                    let is_local = self.read(offset, &mut instruction.next, 1)? == 1;
+                    let index = self.read(offset, &mut instruction.next, 1)?;
+                    // Captured locals are checked against the stack depth later
+                    if !is_local && index >= self.function.upvalue_count {
+                        return Err(self.error(offset, VerifyErrorKind::UpvalueOutOfRange(index)));
+                    }
+                }
+            }
+            Operand::Invoke { is_long } => {
+                let index = self.read(offset, &mut instruction.next, index_len(is_long))?;
+                self.constant(offset, index, Some(ObjType::String))?;
+                instruction.byte = self.read(offset, &mut instruction.next, 1)?;
+            }
+        }
+
+        Ok(instruction)
+    }
+
+    /// Reads a big-endian operand of `len` bytes at `cursor`, and advances it
+    fn read(&self, offset: usize, cursor: &mut usize, len: usize) -> Result<usize, VerifyError> {
+        let Some(bytes) = self.chunk.code.get(*cursor..*cursor + len) else {
+            return Err(self.error(offset, VerifyErrorKind::TruncatedOperand));
+        };
+
+        *cursor += len;
+        Ok(bytes
+            .iter()
+            .fold(0, |operand, &byte| operand << 8 | byte as usize))
+    }
+
+    fn constant(
+        &self,
+        offset: usize,
+        index: usize,
+        expected: Option<ObjType>,
+    ) -> Result<Value, VerifyError> {
+        let Some(&constant) = self.chunk.constants.get(index) else {
+            return Err(self.error(offset, VerifyErrorKind::ConstantOutOfRange(index)));
+        };
+
+        match expected {
+            Some(expected) if !constant.is_obj_type(expected) => Err(self.error(
+                offset,
+                VerifyErrorKind::WrongConstantType { index, expected },
+            )),
+            _ => Ok(constant),
+        }
+    }
+
+    /// Every byte of the code must have a location, for runtime errors
+    fn check_locations(&self) -> Result<(), VerifyError> {
+        let lines = &self.chunk.lines;
+        if lines.first().is_none_or(|start| start.offset != 0) {
+            return Err(self.error(0, VerifyErrorKind::InvalidLocationTable));
+        }
+
+        for run in lines.windows(2) {
+            if run[1].offset <= run[0].offset || run[1].offset >= self.chunk.code.len() {
+                return Err(self.error(run[1].offset, VerifyErrorKind::InvalidLocationTable));
+            }
+        }
+
+        let Some(spans) = chunk::decode_spans(&self.chunk.spans) else {
+            return Err(self.error(0, VerifyErrorKind::InvalidLocationTable));
+        };
+        if spans.first().is_none_or(|start| start.offset != 0) {
+            return Err(self.error(0, VerifyErrorKind::InvalidLocationTable));
+        }
+
+        for run in spans.windows(2) {
+            if run[1].offset <= run[0].offset || run[1].offset >= self.chunk.code.len() {
+                return Err(self.error(run[1].offset, VerifyErrorKind::InvalidLocationTable));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Follows every path through the code, tracking how many values are on the stack,
+    /// and returns the most there can be
+    fn check_stack(&self, instructions: &[Option<Instruction>]) -> Result<usize, VerifyError> {
+        let code = &self.chunk.code;
+        let mut depths = vec![None; code.len()];
+        // The callee and its arguments are already in the frame's first slots
+        let mut pending = vec![(0, self.function.arity + 1)];
+        let mut max_depth = self.function.arity + 1;
+
+        while let Some((offset, depth)) = pending.pop() {
+            match depths[offset] {
+                Some(expected) if expected == depth => continue,
+                Some(expected) => {
+                    return Err(self.error(
+                        offset,
+                        VerifyErrorKind::InconsistentStackDepth {
+                            expected,
+                            found: depth,
+                        },
+                    ));
+                }
+                None => depths[offset] = Some(depth),
+            }
+
+            let instruction = instructions[offset].expect("paths to only reach instructions");
+            match instruction.opcode {
+                OpCode::GetLocal | OpCode::SetLocal if instruction.byte >= depth => {
+                    return Err(
+                        self.error(offset, VerifyErrorKind::LocalOutOfRange(instruction.byte))
+                    );
+                }
+                OpCode::Closure | OpCode::ClosureLong => {
+                    let descriptors = instruction.next - 2 * instruction.upvalue_count;
+                    for upvalue in code[descriptors..instruction.next].chunks(2) {
+                        let (is_local, slot) = (upvalue[0] == 1, upvalue[1] as usize);
+                        if is_local && slot >= depth {
+                            return Err(self.error(offset, VerifyErrorKind::LocalOutOfRange(slot)));
+                        }
+                    }
+                }
+                _ => {}
+            }
+
+            let (pops, pushes) = stack_effect(&instruction);
+            if depth < pops {
+                return Err(self.error(offset, VerifyErrorKind::StackUnderflow));
+            }
+            let depth = depth - pops + pushes;
+            if depth > STACK_MAX {
+                return Err(self.error(offset, VerifyErrorKind::StackOverflow));
+            }
+            max_depth = max_depth.max(depth);
+
+            let falls_through = match instruction.opcode {
+                OpCode::Return => false,
+                OpCode::Jump | OpCode::Loop => {
+                    pending.push((instruction.target, depth));
+                    false
+                }
+                OpCode::JumpIfFalse => {
+                    pending.push((instruction.target, depth));
+                    true
+                }
+                _ => true,
+            };
+            if falls_through {
+                if instruction.next == code.len() {
+                    return Err(self.error(offset, VerifyErrorKind::FallsOffEnd));
+                }
+                pending.push((instruction.next, depth));
+            }
+        }
+
+        Ok(max_depth)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bytecode,
+        chunk::OP,
+        compiler::Compiler,
+        object::Obj,
+        scanner::Location,
+        vm::{InterpretResult, VM},
+    };
+
+    fn function(code: &[u8]) -> ObjFunction {
+        let mut function = ObjFunction::new();
+        for &byte in code {
+            function.chunk.write(byte, Location::default());
+        }
+        function
+    }
+
+    fn error(function: &ObjFunction) -> VerifyErrorKind {
+        verify(function)
+            .expect_err("the function to be rejected")
+            .kind
+    }
+
+    #[test]
+    fn accepts_valid_code() {
+        let mut function = function(&[OP::CONSTANT, 0, OP::GET_LOCAL, 0, OP::ADD, OP::RETURN]);
+        function.chunk.constants.push(Value::Number(1.0));
+
+        // The callee, the constant and the local
+        assert_eq!(verify(&function).unwrap(), 3);
+    }
+
+    #[test]
+    fn rejects_empty_code() {
+        assert!(matches!(error(&function(&[])), VerifyErrorKind::EmptyCode));
+    }
+
+    #[test]
+    fn rejects_missing_lines() {
+        let mut function = function(&[OP::NIL, OP::RETURN]);
+        function.chunk.lines.clear();
+
+        assert!(matches!(
+            error(&function),
+            VerifyErrorKind::InvalidLocationTable
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_spans() {
+        let mut function = function(&[OP::NIL, OP::RETURN]);
+        // The high bit says another byte of the varint follows
+        function.chunk.spans.push(0x80);
+
+        assert!(matches!(
+            error(&function),
+            VerifyErrorKind::InvalidLocationTable
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_opcodes() {
+        assert!(matches!(
+            error(&function(&[u8::MAX])),
+            VerifyErrorKind::InvalidOpcode(u8::MAX)
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_operands() {
+        assert!(matches!(
+            error(&function(&[OP::CONSTANT_LONG, 0, 0])),
+            VerifyErrorKind::TruncatedOperand
+        ));
+    }
+
+    #[test]
+    fn rejects_missing_constants() {
+        assert!(matches!(
+            error(&function(&[OP::CONSTANT, 0, OP::RETURN])),
+            VerifyErrorKind::ConstantOutOfRange(0)
+        ));
+    }
+
+    #[test]
+    fn rejects_names_that_are_not_strings() {
+        let mut function = function(&[OP::GET_GLOBAL, 0, OP::RETURN]);
+        function.chunk.constants.push(Value::Number(1.0));
+
+        assert!(matches!(
+            error(&function),
+            VerifyErrorKind::WrongConstantType {
+                index: 0,
+                expected: ObjType::String
+            }
+        ));
+    }
+
+    #[test]
+    fn rejects_closures_of_constants_that_are_not_functions() {
+        let mut function = function(&[OP::CLOSURE, 0, OP::RETURN]);
+        function.chunk.constants.push(Value::Nil);
+
+        assert!(matches!(
+            error(&function),
+            VerifyErrorKind::WrongConstantType {
+                index: 0,
+                expected: ObjType::Function
+            }
+        ));
+    }
+
+    #[test]
+    fn rejects_jumps_out_of_the_code() {
+        assert!(matches!(
+            error(&function(&[OP::JUMP, 0, 10, OP::NIL, OP::RETURN])),
+            VerifyErrorKind::InvalidJumpTarget(13)
+        ));
+        assert!(matches!(
+            error(&function(&[OP::NIL, OP::LOOP, 0, 10, OP::RETURN])),
+            VerifyErrorKind::InvalidJumpTarget(-6)
+        ));
+    }
+
+    #[test]
+    fn rejects_jumps_into_operands() {
+        assert!(matches!(
+            error(&function(&[OP::JUMP, 0, 1, OP::GET_LOCAL, 0, OP::RETURN])),
+            VerifyErrorKind::InvalidJumpTarget(4)
+        ));
+    }
+
+    #[test]
+    fn rejects_falling_off_the_end() {
+        assert!(matches!(
+            error(&function(&[OP::NIL])),
+            VerifyErrorKind::FallsOffEnd
+        ));
+    }
+
+    #[test]
+    fn rejects_stack_underflow() {
+        // Only the callee is on the stack
+        assert!(matches!(
+            error(&function(&[OP::ADD, OP::RETURN])),
+            VerifyErrorKind::StackUnderflow
+        ));
+    }
+
+    #[test]
+    fn rejects_stack_overflow() {
+        let mut code = vec![OP::NIL; STACK_MAX];
+        code.push(OP::RETURN);
+
+        assert!(matches!(
+            error(&function(&code)),
+            VerifyErrorKind::StackOverflow
+        ));
+    }
+
+    #[test]
+    fn rejects_inconsistent_stack_depths() {
+        // The jump skips the second `nil`, so the return is reached with two different depths
+        let code = [OP::NIL, OP::JUMP_IF_FALSE, 0, 1, OP::NIL, OP::RETURN];
+
+        assert!(matches!(
+            error(&function(&code)),
+            VerifyErrorKind::InconsistentStackDepth { .. }
+        ));
+    }
+
+    #[test]
+    fn rejects_locals_above_the_stack() {
+        assert!(matches!(
+            error(&function(&[OP::GET_LOCAL, 1, OP::RETURN])),
+            VerifyErrorKind::LocalOutOfRange(1)
+        ));
+    }
+
+    #[test]
+    fn rejects_captured_locals_above_the_stack() {
+        let mut vm = VM::new();
+        let captured = vm.new_function();
+        // SAFETY: The function has just been allocated, and nothing is allocated while it is used
+        unsafe { (*captured).upvalue_count = 1 };
+        let mut function = function(&[OP::CLOSURE, 0, 1, 5, OP::RETURN]);
+        function
+            .chunk
+            .constants
+            .push(Value::Obj(captured as *mut Obj));
+
+        assert!(matches!(
+            error(&function),
+            VerifyErrorKind::LocalOutOfRange(5)
+        ));
+    }
+
+    #[test]
+    fn rejects_missing_upvalues() {
+        assert!(matches!(
+            error(&function(&[OP::GET_UPVALUE, 0, OP::RETURN])),
+            VerifyErrorKind::UpvalueOutOfRange(0)
+        ));
+    }
+
+    #[test]
+    fn rejects_too_many_upvalues() {
+        let mut function = function(&[OP::NIL, OP::RETURN]);
+        function.upvalue_count = u32::MAX as usize;
+
+        assert!(matches!(
+            error(&function),
+            VerifyErrorKind::InvalidUpvalueCount(_)
+        ));
+    }
+
+    #[test]
+    fn rejects_scripts_with_upvalues() {
+        let mut function = function(&[OP::GET_UPVALUE, 0, OP::RETURN]);
+        function.upvalue_count = 1;
+
+        assert!(verify(&function).is_ok());
+        assert!(matches!(
+            verify_script(&function)
+                .expect_err("the script to be rejected")
+                .kind,
+            VerifyErrorKind::InvalidUpvalueCount(1)
+        ));
+    }
+
+    #[test]
+    fn loaded_code_with_wrong_operand_types_raises_runtime_errors() {
+        let mut vm = VM::new();
+        let function = Compiler::compile("test.lox", "class A { m() {} }", &mut vm).unwrap();
+        // SAFETY: The function has just been compiled, and nothing is allocated until it is serialized
+        let bytes = unsafe {
+            // Both take a one byte constant, so the class' name is pushed instead of the class
+            let code = &mut (*function).chunk.code;
+            let class = code.iter().position(|&byte| byte == OP::CLASS).unwrap();
+            code[class] = OP::CONSTANT;
+            bytecode::serialize("test.lox", &*function)
+        };
+
+        let result = vm
+            .interpret_bytecode(&bytes)
+            .expect("the code to be verified");
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+    }
+}
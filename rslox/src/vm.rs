@@ -1,67 +1,292 @@
-use std::{ops::Range, pin::Pin, ptr};
+use std::{
+    fmt::{self, Display},
+    ops::Range,
+    pin::Pin,
+    ptr,
+};
 
+#[cfg(feature = "debug_trace_execution")]
+use crate::disassembler::disassemble_instruction;
 use crate::{
+    bytecode::{self, LoadError},
     chunk::{Chunk, OP},
-    compiler,
-    disassembler::disassemble_instruction,
-    value::{Value, print_value},
+    compiler::{self, CompileError},
+    diagnostic::{Diagnostic, Severity},
+    memory::GC_MIN_THRESHOLD,
+    native::{self, IntoNative},
+    object::{
+        NativeFn, Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjList,
+        ObjNative, ObjString, ObjType, ObjUpvalue, hash_string,
+    },
+    scanner::Location,
+    table::Table,
+    value::{Value, print_value, sealed::Token, values_equal},
 };
 
-// const STACK_MAX: usize = 256;
-const STACK_MAX: usize = 256;
+const FRAMES_MAX: usize = 64;
+/// Every call frame can address 256 locals
+pub(crate) const STACK_MAX: usize = FRAMES_MAX * (u8::MAX as usize + 1);
 
-pub struct VM {
-    chunk: Pin<Box<Chunk>>,
+/// Pops two number operands, applies the operator and pushes the result wrapped in `$value_type`.
+/// Returns from the enclosing function with a runtime error if either operand is not a number.
+macro_rules! binary_op {
+    ($vm:ident, $value_type:path, $op:tt) => {{
+        let (Value::Number(b), Value::Number(a)) = ($vm.peek(0), $vm.peek(1)) else {
+            return $vm.runtime_error("Operands must be numbers.");
+        };
+        // TODO(optimisation): We could mutate the value in place through the stack pointer
+        $vm.pop();
+        $vm.pop();
+        $vm.push($value_type(a $op b));
+    }};
+}
+
+/// An ongoing function call
+pub(crate) struct CallFrame {
+    pub(crate) closure: *mut ObjClosure,
+    /// The frame's own instruction pointer, the caller's is kept in its own frame to return to
     ip: *const u8,
-    ip_range: Range<*const u8>,
+    /// The frame's window into the VM's stack, slot 0 holds the called function, followed by the arguments
+    slots: *mut Value,
+}
+
+impl CallFrame {
+    fn closure(&self) -> &ObjClosure {
+        // SAFETY: Closures are live while they are being called
+        unsafe { &*self.closure }
+    }
+
+    fn function(&self) -> &ObjFunction {
+        self.closure().function()
+    }
+
+    /// Offset of the instruction being executed in the function's chunk
+    fn instruction_offset(&self) -> usize {
+        // The ip has already been advanced past the instruction's opcode
+        // SAFETY: The ip always points into the function's chunk
+        unsafe {
+            self.ip
+                .offset_from_unsigned(self.function().chunk.code.as_ptr())
+                - 1
+        }
+    }
+}
+
+pub struct VM {
+    pub(crate) frames: Vec<CallFrame>,
+    /// Upvalues pointing to variables still on the stack, sorted by their stack slot, from the top of the stack
+    pub(crate) open_upvalues: *mut ObjUpvalue,
 
     // TODO: I think I need to store the underlying struct here for the pointers to work
     #[allow(unused)]
     stack: Pin<Box<[Value; STACK_MAX]>>,
-    stack_top: *mut Value,
-    stack_ptr_range: Range<*mut Value>,
+    pub(crate) stack_top: *mut Value,
+    pub(crate) stack_ptr_range: Range<*mut Value>,
+
+    pub(crate) globals: Table,
+    /// Every string is interned in this set (only the keys are used), so equal strings are the same object
+    pub(crate) strings: Table,
+
+    /// Head of the linked list of every allocated object
+    pub(crate) objects: *mut Obj,
+    /// Marked objects whose references have not been traced yet
+    pub(crate) gray_stack: Vec<*mut Obj>,
+    pub(crate) bytes_allocated: usize,
+    /// Collect garbage when `bytes_allocated` exceeds this
+    pub(crate) next_gc: usize,
+    /// Functions being compiled or loaded, they must be kept alive until they are finished
+    pub(crate) compiler_roots: Vec<*mut ObjFunction>,
+    /// Values being built by host function conversions, which are not on the stack
+    pub(crate) temp_roots: Vec<Value>,
+
+    /// Source file of the running script, for runtime errors
+    pub(crate) file_name: String,
+
+    /// Name of the initializer method, interned once so looking it up on every instantiation is cheap
+    pub(crate) init_string: *mut ObjString,
 }
 
 pub enum InterpretResult {
     Ok,
-    #[allow(unused)]
-    CompileError,
-    #[allow(unused)]
-    RuntimeError,
+    CompileError(Vec<CompileError>),
+    RuntimeError(RuntimeError),
+}
+
+#[derive(Debug, Clone)]
+pub struct RuntimeError {
+    pub message: String,
+    /// The source file of the script, which is not the file that was run for compiled scripts
+    pub file_name: String,
+    /// The calls active when the error happened, from the innermost one to the top-level script
+    pub trace: Vec<TraceFrame>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceFrame {
+    /// `None` for the top-level script
+    pub function: Option<String>,
+    pub location: Location,
+}
+
+impl RuntimeError {
+    /// The error is reported at the instruction that raised it
+    pub fn diagnostic(&self) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            code: "runtime",
+            message: self.message.clone(),
+            location: self
+                .trace
+                .first()
+                .map(|frame| frame.location)
+                .unwrap_or_default(),
+            labels: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+}
+
+impl Display for RuntimeError {
+    /// The message with the stack trace, from the innermost call to the top-level script
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for frame in &self.trace {
+            let location = frame.location;
+            write!(
+                f,
+                "\n[{}:{}:{}] ",
+                self.file_name, location.line, location.column
+            )?;
+            match &frame.function {
+                Some(name) => write!(f, "in {name}()")?,
+                None => write!(f, "in script")?,
+            }
+        }
+        Ok(())
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
         let mut vm = VM {
-            chunk: Box::pin(Chunk::new()),
-            ip: ptr::null(),
-            ip_range: Range::default(),
-            stack: Box::pin([0.0; STACK_MAX]),
+            frames: Vec::with_capacity(FRAMES_MAX),
+            open_upvalues: ptr::null_mut(),
+            stack: Box::pin([Value::Nil; STACK_MAX]),
             stack_top: ptr::null_mut(),
             stack_ptr_range: Range::default(),
+            globals: Table::new(),
+            strings: Table::new(),
+            objects: ptr::null_mut(),
+            gray_stack: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: GC_MIN_THRESHOLD,
+            compiler_roots: Vec::new(),
+            temp_roots: Vec::new(),
+            file_name: String::new(),
+            init_string: ptr::null_mut(),
         };
 
-        vm.ip = vm.chunk.code.as_ptr();
-        vm.ip_range = vm.chunk.code.as_ptr_range();
         vm.stack_top = vm.stack.as_mut_ptr();
         vm.stack_ptr_range = vm.stack.as_mut_ptr_range();
 
+        vm.init_string = vm.copy_string("init");
+
+        vm.register("clock", native::clock);
+
         vm
     }
 
-    pub fn interpret(&mut self, source: &str) -> InterpretResult {
-        let chunk = match compiler::Compiler::compile(source) {
-            Ok(chunk) => chunk,
-            Err(_) => return InterpretResult::CompileError,
+    /// Exposes a Rust closure to Lox scripts as a global, e.g. `vm.register("add", |a: f64, b: f64| a + b)`.
+    /// The arguments are converted with `FromLox`, calling it with the wrong number or types of arguments
+    /// raises a runtime error. The result is converted with `IntoLox`, returning `Err` raises a runtime error.
+    pub fn register<Args, F: IntoNative<Args>>(&mut self, name: &str, function: F) {
+        self.define_native_fn(name, F::ARITY, function.into_native(Token(())));
+    }
+
+    /// Exposes a host function taking the arguments as they are to Lox scripts as a global,
+    /// for functions `register` can't express, e.g. ones accepting values of any type.
+    /// Objects in the arguments are owned by the VM, and they are only valid during the call,
+    /// so the function can only return objects it was passed, returning any other object raises a runtime error.
+    pub fn define_native<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + 'static,
+    {
+        // The function doesn't get the VM, so it cannot run Lox code while the VM is running
+        let native = move |_: &mut VM, args: &[Value]| {
+            let result = function(args)?;
+            match result {
+                Value::Obj(_) if !args.contains(&result) => {
+                    Err("Native functions can only return objects they were passed.".to_owned())
+                }
+                _ => Ok(result),
+            }
+        };
+        self.define_native_fn(name, arity, Box::new(native));
+    }
+
+    fn define_native_fn(&mut self, name: &str, arity: usize, function: NativeFn) {
+        // Both objects are kept on the stack, so they are reachable if allocating the other triggers a collection
+        let name = self.copy_string(name);
+        self.push(Value::Obj(name as *mut Obj));
+        let native = self.allocate_object(ObjNative::new(arity, function));
+        self.push(Value::Obj(native as *mut Obj));
+
+        self.globals.set(name, self.peek(0));
+
+        self.pop();
+        self.pop();
+    }
+
+    /// `file_name` is only used in error messages
+    pub fn interpret(&mut self, file_name: &str, source: &str) -> InterpretResult {
+        self.file_name = file_name.to_owned();
+        let function = match compiler::Compiler::compile(file_name, source, self) {
+            Ok(function) => function,
+            Err(errors) => return InterpretResult::CompileError(errors),
+        };
+
+        self.interpret_function(function)
+    }
+
+    /// Like `interpret`, but the value of the script's final expression statement is left on the stack when it succeeds,
+    /// so it stays rooted until the caller has converted and popped it
+    pub(crate) fn evaluate(&mut self, file_name: &str, source: &str) -> InterpretResult {
+        self.file_name = file_name.to_owned();
+        let function = match compiler::Compiler::compile_eval(file_name, source, self) {
+            Ok(function) => function,
+            Err(errors) => return InterpretResult::CompileError(errors),
         };
 
-        dbg!(self.stack.as_mut_ptr_range(), &self.stack_ptr_range);
+        self.run_script(function)
+    }
 
-        self.chunk = Box::pin(chunk);
-        self.ip = self.chunk.code.as_ptr();
-        self.ip_range = self.chunk.code.as_ptr_range();
+    /// Runs a script compiled ahead of time by `bytecode::serialize`
+    pub fn interpret_bytecode(&mut self, bytes: &[u8]) -> Result<InterpretResult, LoadError> {
+        let (file_name, function) = bytecode::deserialize(bytes, self)?;
+        self.file_name = file_name;
+        Ok(self.interpret_function(function))
+    }
 
-        dbg!(self.stack.as_mut_ptr_range(), &self.stack_ptr_range);
+    fn interpret_function(&mut self, function: *mut ObjFunction) -> InterpretResult {
+        let result = self.run_script(function);
+        if let InterpretResult::Ok = result {
+            // Discard the script's result
+            self.pop();
+        }
+
+        result
+    }
+
+    /// Runs the script, and leaves its result on the stack if it succeeds
+    fn run_script(&mut self, function: *mut ObjFunction) -> InterpretResult {
+        // The top-level script is called like any other function
+        self.push(Value::Obj(function as *mut Obj));
+        let closure = self.new_closure(function);
+        self.pop();
+        self.push(Value::Obj(closure as *mut Obj));
+        if let Err(error) = self.call(closure, 0) {
+            return error;
+        }
 
         self.run()
     }
@@ -83,16 +308,30 @@ impl VM {
                 }
                 println!();
 
-                disassemble_instruction(&self.chunk, unsafe {
-                    self.ip.offset_from_unsigned(self.ip_range.start)
+                let frame = self.frame();
+                // SAFETY: The ip always points into the function's chunk
+                disassemble_instruction(&frame.function().chunk, unsafe {
+                    frame
+                        .ip
+                        .offset_from_unsigned(frame.function().chunk.code.as_ptr())
                 });
             }
 
             match self.read_byte() {
                 OP::RETURN => {
-                    print_value(self.pop());
-                    println!();
-                    return InterpretResult::Ok;
+                    let result = self.pop();
+                    let frame = self.frames.pop().expect("to return from a call frame");
+                    // The callee's locals are discarded, so the ones captured must be moved to the heap
+                    self.close_upvalues(frame.slots);
+
+                    // Discard the callee's slots, including its arguments and the function itself
+                    self.stack_top = frame.slots;
+                    self.push(result);
+
+                    if self.frames.is_empty() {
+                        // The script's result replaces the top-level script function
+                        return InterpretResult::Ok;
+                    }
                 }
                 OP::CONSTANT => {
                     let value = self.read_constant();
@@ -102,67 +341,681 @@ impl VM {
                     let value = self.read_constant_long();
                     self.push(value);
                 }
-                OP::NEGATE => {
-                    // TODO(optimisation): We could mutate the value in place through the stack pointer
-                    let value = -self.pop();
+                OP::POP => {
+                    self.pop();
+                }
+                OP::GET_LOCAL => {
+                    let slot = self.read_byte() as usize;
+                    // SAFETY: The compiler only emits slots of locals that are on the stack
+                    let value = unsafe { *self.frame().slots.add(slot) };
                     self.push(value);
                 }
-                OP::ADD => {
-                    // TODO(optimisation): We could mutate the value in place through the stack pointer
-                    let b = self.pop();
-                    let a = self.pop();
-                    self.push(a + b);
+                OP::SET_LOCAL => {
+                    let slot = self.read_byte() as usize;
+                    // SAFETY: The compiler only emits slots of locals that are on the stack
+                    // Assignment is an expression, so we leave the value on the stack
+                    unsafe { *self.frame().slots.add(slot) = self.peek(0) };
                 }
-                OP::SUBTRACT => {
-                    // TODO(optimisation): We could mutate the value in place through the stack pointer
-                    let b = self.pop();
-                    let a = self.pop();
-                    self.push(a - b);
+                OP::DEFINE_GLOBAL => {
+                    let name = self.read_string();
+                    self.define_global(name);
                 }
-                OP::MULTIPLY => {
-                    // TODO(optimisation): We could mutate the value in place through the stack pointer
+                OP::DEFINE_GLOBAL_LONG => {
+                    let name = self.read_string_long();
+                    self.define_global(name);
+                }
+                OP::GET_GLOBAL => {
+                    let name = self.read_string();
+                    if let Err(error) = self.get_global(name) {
+                        return error;
+                    }
+                }
+                OP::GET_GLOBAL_LONG => {
+                    let name = self.read_string_long();
+                    if let Err(error) = self.get_global(name) {
+                        return error;
+                    }
+                }
+                OP::SET_GLOBAL => {
+                    let name = self.read_string();
+                    if let Err(error) = self.set_global(name) {
+                        return error;
+                    }
+                }
+                OP::SET_GLOBAL_LONG => {
+                    let name = self.read_string_long();
+                    if let Err(error) = self.set_global(name) {
+                        return error;
+                    }
+                }
+                OP::NIL => self.push(Value::Nil),
+                OP::TRUE => self.push(Value::Bool(true)),
+                OP::FALSE => self.push(Value::Bool(false)),
+                OP::EQUAL => {
                     let b = self.pop();
                     let a = self.pop();
-                    self.push(a * b);
+                    self.push(Value::Bool(values_equal(a, b)));
                 }
-                OP::DIVIDE => {
+                OP::GREATER => binary_op!(self, Value::Bool, >),
+                OP::LESS => binary_op!(self, Value::Bool, <),
+                OP::NEGATE => {
                     // TODO(optimisation): We could mutate the value in place through the stack pointer
-                    let b = self.pop();
-                    let a = self.pop();
-                    self.push(a / b);
+                    let Value::Number(value) = self.peek(0) else {
+                        return self.runtime_error("Operand must be a number.");
+                    };
+                    self.pop();
+                    self.push(Value::Number(-value));
+                }
+                OP::ADD => match (self.peek(0), self.peek(1)) {
+                    (Value::Number(_), Value::Number(_)) => binary_op!(self, Value::Number, +),
+                    (b, a) if a.as_string().is_some() && b.as_string().is_some() => {
+                        self.concatenate()
+                    }
+                    _ => {
+                        return self.runtime_error("Operands must be two numbers or two strings.");
+                    }
+                },
+                OP::SUBTRACT => binary_op!(self, Value::Number, -),
+                OP::MULTIPLY => binary_op!(self, Value::Number, *),
+                OP::DIVIDE => binary_op!(self, Value::Number, /),
+                OP::NOT => {
+                    let value = self.pop();
+                    self.push(Value::Bool(value.is_falsey()));
+                }
+                OP::JUMP => {
+                    let offset = self.read_short() as usize;
+                    let frame = self.frame_mut();
+                    // SAFETY: Jump targets are inside the chunk, the compiler emits them so and the verifier checks loaded code
+                    frame.ip = unsafe { frame.ip.add(offset) };
+                }
+                OP::JUMP_IF_FALSE => {
+                    let offset = self.read_short() as usize;
+                    if self.peek(0).is_falsey() {
+                        let frame = self.frame_mut();
+                        // SAFETY: Jump targets are inside the chunk, the compiler emits them so and the verifier checks loaded code
+                        frame.ip = unsafe { frame.ip.add(offset) };
+                    }
+                }
+                OP::LOOP => {
+                    let offset = self.read_short() as usize;
+                    let frame = self.frame_mut();
+                    // SAFETY: Jump targets are inside the chunk, the compiler emits them so and the verifier checks loaded code
+                    frame.ip = unsafe { frame.ip.sub(offset) };
+                }
+                OP::CALL => {
+                    let arg_count = self.read_byte() as usize;
+                    if let Err(error) = self.call_value(self.peek(arg_count), arg_count) {
+                        return error;
+                    }
+                }
+                OP::PRINT => {
+                    print_value(self.pop());
+                    println!();
+                }
+                op @ (OP::CLOSURE | OP::CLOSURE_LONG) => {
+                    let function = if op == OP::CLOSURE {
+                        self.read_constant()
+                    } else {
+                        self.read_constant_long()
+                    };
+                    let Value::Obj(function) = function else {
+                        unreachable!("closure constant to be a function");
+                    };
+                    let closure = self.new_closure(function as *mut ObjFunction);
+                    self.push(Value::Obj(closure as *mut Obj));
+
+                    // SAFETY: We have just allocated the closure
+                    let upvalue_count = unsafe { (*closure).function().upvalue_count };
+                    for _ in 0..upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        let upvalue = if is_local {
+                            // SAFETY: The compiler only emits slots of locals that are on the stack
+                            self.capture_upvalue(unsafe { self.frame().slots.add(index) })
+                        } else {
+                            self.frame().closure().upvalues[index]
+                        };
+                        // SAFETY: Same as above
+                        unsafe { (*closure).upvalues.push(upvalue) };
+                    }
+                }
+                OP::GET_UPVALUE => {
+                    let slot = self.read_byte() as usize;
+                    // SAFETY: Upvalues always point to a live variable, on the stack or closed over
+                    let value = unsafe { *(*self.frame().closure().upvalues[slot]).location };
+                    self.push(value);
+                }
+                OP::SET_UPVALUE => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.frame().closure().upvalues[slot];
+                    // SAFETY: Upvalues always point to a live variable, on the stack or closed over
+                    unsafe { *(*upvalue).location = self.peek(0) };
+                }
+                OP::CLOSE_UPVALUE => {
+                    // The variable at the top of the stack goes out of scope
+                    // SAFETY: The compiler only emits this when a captured local is on top of the stack
+                    self.close_upvalues(unsafe { self.stack_top.sub(1) });
+                    self.pop();
+                }
+                OP::CLASS => {
+                    let name = self.read_string();
+                    let class = self.new_class(name);
+                    self.push(Value::Obj(class as *mut Obj));
+                }
+                OP::CLASS_LONG => {
+                    let name = self.read_string_long();
+                    let class = self.new_class(name);
+                    self.push(Value::Obj(class as *mut Obj));
+                }
+                OP::GET_PROPERTY => {
+                    let name = self.read_string();
+                    if let Err(error) = self.get_property(name) {
+                        return error;
+                    }
+                }
+                OP::GET_PROPERTY_LONG => {
+                    let name = self.read_string_long();
+                    if let Err(error) = self.get_property(name) {
+                        return error;
+                    }
+                }
+                OP::SET_PROPERTY => {
+                    let name = self.read_string();
+                    if let Err(error) = self.set_property(name) {
+                        return error;
+                    }
+                }
+                OP::SET_PROPERTY_LONG => {
+                    let name = self.read_string_long();
+                    if let Err(error) = self.set_property(name) {
+                        return error;
+                    }
+                }
+                OP::INHERIT => {
+                    let Some(superclass) = self.peek(1).as_class_ptr() else {
+                        return self.runtime_error("Superclass must be a class.");
+                    };
+                    // Only loaded bytecode can inherit into something else, the compiler always emits a class
+                    let Some(subclass) = self.peek(0).as_class_ptr() else {
+                        return self.runtime_error("Can only inherit into a class.");
+                    };
+
+                    // Methods are copied down, so inherited methods are looked up as fast as the class' own ones.
+                    // The subclass' methods are only added after this, so they override the inherited ones.
+                    // SAFETY: Both classes are live objects on the stack
+                    unsafe { (*subclass).methods.add_all(&(*superclass).methods) };
+                    self.pop();
+                }
+                OP::GET_SUPER => {
+                    let name = self.read_string();
+                    let superclass = match self.pop_class() {
+                        Ok(superclass) => superclass,
+                        Err(error) => return error,
+                    };
+                    if let Err(error) = self.bind_method(superclass, name) {
+                        return error;
+                    }
+                }
+                OP::GET_SUPER_LONG => {
+                    let name = self.read_string_long();
+                    let superclass = match self.pop_class() {
+                        Ok(superclass) => superclass,
+                        Err(error) => return error,
+                    };
+                    if let Err(error) = self.bind_method(superclass, name) {
+                        return error;
+                    }
+                }
+                OP::INVOKE => {
+                    let name = self.read_string();
+                    let arg_count = self.read_byte() as usize;
+                    if let Err(error) = self.invoke(name, arg_count) {
+                        return error;
+                    }
+                }
+                OP::INVOKE_LONG => {
+                    let name = self.read_string_long();
+                    let arg_count = self.read_byte() as usize;
+                    if let Err(error) = self.invoke(name, arg_count) {
+                        return error;
+                    }
+                }
+                OP::SUPER_INVOKE => {
+                    let name = self.read_string();
+                    let arg_count = self.read_byte() as usize;
+                    let superclass = match self.pop_class() {
+                        Ok(superclass) => superclass,
+                        Err(error) => return error,
+                    };
+                    if let Err(error) = self.invoke_from_class(superclass, name, arg_count) {
+                        return error;
+                    }
+                }
+                OP::SUPER_INVOKE_LONG => {
+                    let name = self.read_string_long();
+                    let arg_count = self.read_byte() as usize;
+                    let superclass = match self.pop_class() {
+                        Ok(superclass) => superclass,
+                        Err(error) => return error,
+                    };
+                    if let Err(error) = self.invoke_from_class(superclass, name, arg_count) {
+                        return error;
+                    }
+                }
+                OP::METHOD => {
+                    let name = self.read_string();
+                    if let Err(error) = self.define_method(name) {
+                        return error;
+                    }
+                }
+                OP::METHOD_LONG => {
+                    let name = self.read_string_long();
+                    if let Err(error) = self.define_method(name) {
+                        return error;
+                    }
+                }
+                unknown_opcode => {
+                    return self.runtime_error(&format!("Unknown opcode: {unknown_opcode:04}"));
                 }
-                unknown_opcode => panic!("Unknown opcode: {unknown_opcode:04}"),
             }
         }
     }
 
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("to be executing a call frame")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames
+            .last_mut()
+            .expect("to be executing a call frame")
+    }
+
+    fn chunk(&self) -> &Chunk {
+        &self.frame().function().chunk
+    }
+
     fn read_byte(&mut self) -> u8 {
-        // TODO(safety): What guarantees that we are in range of the chunk.code slice?
-        let byte = unsafe { self.ip.read() };
-        // TODO(safety): What guarantees that we remain in range of the chunk.code slice?
-        self.ip = unsafe { self.ip.add(1) };
+        let frame = self.frame_mut();
+        // SAFETY: Operands are inside the chunk, and every path returns before running off its end.
+        // The compiler emits code like this, and the verifier checks it for loaded code.
+        let byte = unsafe { frame.ip.read() };
+        // SAFETY: Same as above, at most it points one past the end of the code
+        frame.ip = unsafe { frame.ip.add(1) };
         byte
     }
 
+    /// Reads a big-endian 16 bit operand
+    fn read_short(&mut self) -> u16 {
+        let high = self.read_byte() as u16;
+        let low = self.read_byte() as u16;
+        high << 8 | low
+    }
+
     fn read_constant(&mut self) -> Value {
         let index = self.read_byte() as usize;
-        self.chunk.constants[index]
+        self.chunk().constants[index]
     }
 
     fn read_constant_long(&mut self) -> Value {
         let index0 = self.read_byte() as usize;
         let index1 = self.read_byte() as usize;
         let index2 = self.read_byte() as usize;
-        self.chunk.constants[index0 << 16 | index1 << 8 | index2]
+        self.chunk().constants[index0 << 16 | index1 << 8 | index2]
+    }
+
+    fn read_string(&mut self) -> *mut ObjString {
+        self.read_constant()
+            .as_string_ptr()
+            .expect("constant to be a string")
+    }
+
+    fn read_string_long(&mut self) -> *mut ObjString {
+        self.read_constant_long()
+            .as_string_ptr()
+            .expect("constant to be a string")
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), InterpretResult> {
+        if let Value::Obj(obj) = callee {
+            // SAFETY: Values only ever hold pointers to live objects
+            match unsafe { (*obj).typ } {
+                ObjType::Closure => return self.call(obj as *mut ObjClosure, arg_count),
+                ObjType::Native => return self.call_native(obj as *mut ObjNative, arg_count),
+                ObjType::Class => return self.instantiate(obj as *mut ObjClass, arg_count),
+                ObjType::BoundMethod => {
+                    // SAFETY: Values only ever hold pointers to live objects
+                    let bound_method = unsafe { &*(obj as *mut ObjBoundMethod) };
+                    // The receiver takes the place of the callee, so the method can access it as `this` in slot 0
+                    // SAFETY: The callee is on the stack, below its arguments
+                    unsafe { *self.stack_top.sub(arg_count + 1) = bound_method.receiver };
+                    return self.call(bound_method.method, arg_count);
+                }
+                ObjType::String
+                | ObjType::Function
+                | ObjType::Upvalue
+                | ObjType::Instance
+                | ObjType::List => {}
+            }
+        }
+
+        Err(self.runtime_error("Can only call functions and classes."))
+    }
+
+    fn call(&mut self, closure: *mut ObjClosure, arg_count: usize) -> Result<(), InterpretResult> {
+        // SAFETY: Closures being called are live objects on the stack
+        let function = unsafe { (*closure).function() };
+        let arity = function.arity;
+        if arg_count != arity {
+            return Err(
+                self.runtime_error(&format!("Expected {arity} arguments but got {arg_count}."))
+            );
+        }
+
+        // -1 to include the function itself, which is below the arguments
+        // SAFETY: The function and its arguments are on the stack
+        let slots = unsafe { self.stack_top.sub(arg_count + 1) };
+        // Temporaries are above the locals, so a frame can use more than 256 slots,
+        // the stack must have room for every value the function can push
+        // SAFETY: Both pointers are in the range of the stack
+        let free_slots = unsafe { self.stack_ptr_range.end.offset_from(slots) } as usize;
+        if self.frames.len() == FRAMES_MAX || function.max_stack > free_slots {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+
+        self.frames.push(CallFrame {
+            closure,
+            ip: function.chunk.code.as_ptr(),
+            slots,
+        });
+        Ok(())
+    }
+
+    fn instantiate(
+        &mut self,
+        class: *mut ObjClass,
+        arg_count: usize,
+    ) -> Result<(), InterpretResult> {
+        // The class is still on the stack while the instance is allocated, so it can't be collected
+        let instance = self.new_instance(class);
+        // Replace the class with the new instance, which is the receiver of the initializer
+        // SAFETY: The class is on the stack, below its arguments
+        unsafe { *self.stack_top.sub(arg_count + 1) = Value::Obj(instance as *mut Obj) };
+
+        // SAFETY: The class is a live object, it is referenced by its instance
+        match unsafe { (*class).methods.get(self.init_string) } {
+            Some(Value::Obj(initializer)) => self.call(initializer as *mut ObjClosure, arg_count),
+            Some(_) => unreachable!("methods to be closures"),
+            None if arg_count != 0 => {
+                Err(self.runtime_error(&format!("Expected 0 arguments but got {arg_count}.")))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Calls a method on the receiver below the arguments, without creating a bound method
+    fn invoke(&mut self, name: *mut ObjString, arg_count: usize) -> Result<(), InterpretResult> {
+        let Some(instance) = self.peek(arg_count).as_instance_ptr() else {
+            return Err(self.runtime_error("Only instances have methods."));
+        };
+
+        // A field can shadow the method, then it's called like any other value
+        // SAFETY: The instance is a live object on the stack
+        if let Some(value) = unsafe { (*instance).fields.get(name) } {
+            // SAFETY: The receiver is on the stack, below its arguments
+            unsafe { *self.stack_top.sub(arg_count + 1) = value };
+            return self.call_value(value, arg_count);
+        }
+
+        // SAFETY: Same as above
+        self.invoke_from_class(unsafe { (*instance).class }, name, arg_count)
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: *mut ObjClass,
+        name: *mut ObjString,
+        arg_count: usize,
+    ) -> Result<(), InterpretResult> {
+        // SAFETY: The class is a live object, it is referenced by the receiver on the stack
+        let Some(Value::Obj(method)) = (unsafe { (*class).methods.get(name) }) else {
+            return Err(self.undefined_property(name));
+        };
+
+        // The receiver is already in slot 0, where the method expects `this`
+        self.call(method as *mut ObjClosure, arg_count)
+    }
+
+    fn call_native(
+        &mut self,
+        native: *mut ObjNative,
+        arg_count: usize,
+    ) -> Result<(), InterpretResult> {
+        // SAFETY: Natives being called are live objects on the stack
+        let native = unsafe { &*native };
+        if arg_count != native.arity {
+            return Err(self.runtime_error(&format!(
+                "Expected {} arguments but got {arg_count}.",
+                native.arity
+            )));
+        }
+
+        // SAFETY: The arguments are the top arg_count values on the stack
+        let args = unsafe { std::slice::from_raw_parts(self.stack_top.sub(arg_count), arg_count) };
+        let result =
+            (native.function)(self, args).map_err(|message| self.runtime_error(&message))?;
+
+        // Discard the arguments and the native itself
+        // SAFETY: Same as above
+        self.stack_top = unsafe { self.stack_top.sub(arg_count + 1) };
+        self.push(result);
+        Ok(())
+    }
+
+    /// Returns the upvalue of the stack slot, reusing it if it has already been captured,
+    /// so closures capturing the same variable share it
+    fn capture_upvalue(&mut self, local: *mut Value) -> *mut ObjUpvalue {
+        let mut previous: *mut ObjUpvalue = ptr::null_mut();
+        let mut upvalue = self.open_upvalues;
+
+        // SAFETY: Open upvalues are live objects
+        unsafe {
+            while !upvalue.is_null() && (*upvalue).location > local {
+                previous = upvalue;
+                upvalue = (*upvalue).next;
+            }
+
+            if !upvalue.is_null() && (*upvalue).location == local {
+                return upvalue;
+            }
+        }
+
+        let created = self.allocate_object(ObjUpvalue::new(local));
+        // SAFETY: Same as above, and we have just allocated `created`
+        unsafe {
+            (*created).next = upvalue;
+            if previous.is_null() {
+                self.open_upvalues = created;
+            } else {
+                (*previous).next = created;
+            }
+        }
+
+        created
+    }
+
+    /// Moves every variable at or above `last` on the stack into its upvalue
+    fn close_upvalues(&mut self, last: *mut Value) {
+        // SAFETY: Open upvalues are live objects, pointing to live stack slots
+        unsafe {
+            while !self.open_upvalues.is_null() && (*self.open_upvalues).location >= last {
+                let upvalue = &mut *self.open_upvalues;
+                upvalue.closed = *upvalue.location;
+                upvalue.location = &mut upvalue.closed;
+                self.open_upvalues = upvalue.next;
+            }
+        }
+    }
+
+    fn define_global(&mut self, name: *mut ObjString) {
+        self.globals.set(name, self.peek(0));
+        self.pop();
+    }
+
+    fn get_global(&mut self, name: *mut ObjString) -> Result<(), InterpretResult> {
+        let Some(value) = self.globals.get(name) else {
+            return Err(self.undefined_variable(name));
+        };
+
+        self.push(value);
+        Ok(())
+    }
+
+    fn set_global(&mut self, name: *mut ObjString) -> Result<(), InterpretResult> {
+        // Assignment is not declaration, the variable must already exist
+        if self.globals.set(name, self.peek(0)) {
+            self.globals.delete(name);
+            return Err(self.undefined_variable(name));
+        }
+
+        Ok(())
+    }
+
+    fn get_property(&mut self, name: *mut ObjString) -> Result<(), InterpretResult> {
+        let Some(instance) = self.peek(0).as_instance_ptr() else {
+            return Err(self.runtime_error("Only instances have properties."));
+        };
+
+        // Fields shadow methods
+        // SAFETY: The instance is a live object on the stack
+        if let Some(value) = unsafe { (*instance).fields.get(name) } {
+            // Replace the instance with the property's value
+            self.pop();
+            self.push(value);
+            return Ok(());
+        }
+
+        // SAFETY: Same as above
+        self.bind_method(unsafe { (*instance).class }, name)
+    }
+
+    /// Replaces the receiver on top of the stack with the class' method bound to it
+    fn bind_method(
+        &mut self,
+        class: *mut ObjClass,
+        name: *mut ObjString,
+    ) -> Result<(), InterpretResult> {
+        // SAFETY: The class is a live object, it is referenced by the receiver on the stack
+        let Some(Value::Obj(method)) = (unsafe { (*class).methods.get(name) }) else {
+            return Err(self.undefined_property(name));
+        };
+
+        // The receiver stays on the stack while the bound method is allocated, so it can't be collected
+        let bound_method = self.new_bound_method(self.peek(0), method as *mut ObjClosure);
+        self.pop();
+        self.push(Value::Obj(bound_method as *mut Obj));
+        Ok(())
+    }
+
+    /// Pops the superclass, which the compiler loads from the `super` local.
+    /// The verifier doesn't track the types on the stack, so loaded bytecode can have anything there.
+    fn pop_class(&mut self) -> Result<*mut ObjClass, InterpretResult> {
+        match self.pop().as_class_ptr() {
+            Some(class) => Ok(class),
+            None => Err(self.runtime_error("Superclass must be a class.")),
+        }
+    }
+
+    fn define_method(&mut self, name: *mut ObjString) -> Result<(), InterpretResult> {
+        // The compiler always emits a closure on top of a class, but loaded bytecode may not
+        let (Some(class), true) = (
+            self.peek(1).as_class_ptr(),
+            self.peek(0).is_obj_type(ObjType::Closure),
+        ) else {
+            return Err(self.runtime_error("Methods must be functions defined in a class."));
+        };
+
+        // SAFETY: The class is a live object on the stack
+        unsafe { (*class).methods.set(name, self.peek(0)) };
+        self.pop();
+        Ok(())
+    }
+
+    fn undefined_property(&mut self, name: *mut ObjString) -> InterpretResult {
+        // SAFETY: Property names are live string constants of the running function
+        let name = unsafe { &(*name).chars };
+        self.runtime_error(&format!("Undefined property '{name}'."))
+    }
+
+    fn set_property(&mut self, name: *mut ObjString) -> Result<(), InterpretResult> {
+        let Some(instance) = self.peek(1).as_instance_ptr() else {
+            return Err(self.runtime_error("Only instances have fields."));
+        };
+
+        // SAFETY: The instance is a live object on the stack
+        unsafe { (*instance).fields.set(name, self.peek(0)) };
+
+        // Assignment is an expression, so the value replaces the instance on the stack
+        let value = self.pop();
+        self.pop();
+        self.push(value);
+        Ok(())
+    }
+
+    fn undefined_variable(&mut self, name: *mut ObjString) -> InterpretResult {
+        // SAFETY: Global names are live string constants of the running function
+        let name = unsafe { &(*name).chars };
+        self.runtime_error(&format!("Undefined variable '{name}'."))
+    }
+
+    fn concatenate(&mut self) {
+        let b = self
+            .pop()
+            .as_string()
+            .expect("concatenated value to be a string");
+        let a = self
+            .pop()
+            .as_string()
+            .expect("concatenated value to be a string");
+
+        let mut chars = String::with_capacity(a.chars.len() + b.chars.len());
+        chars.push_str(&a.chars);
+        chars.push_str(&b.chars);
+
+        let result = self.take_string(chars);
+        self.push(Value::Obj(result as *mut Obj));
+    }
+
+    fn runtime_error(&mut self, message: &str) -> InterpretResult {
+        let trace = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = frame.function();
+                TraceFrame {
+                    function: (!function.name.is_null()).then(|| function.name().to_owned()),
+                    location: function.chunk.get_location(frame.instruction_offset()),
+                }
+            })
+            .collect();
+
+        self.reset_stack();
+        InterpretResult::RuntimeError(RuntimeError {
+            message: message.to_owned(),
+            file_name: self.file_name.clone(),
+            trace,
+        })
     }
 
     fn reset_stack(&mut self) {
-        self.stack = Box::pin([0.0; STACK_MAX]);
-        self.stack_top = self.stack.as_mut_ptr();
-        self.stack_ptr_range = self.stack.as_mut_ptr_range();
+        // Closures which outlive the error, e.g. stored in globals, still point at the discarded slots
+        self.close_upvalues(self.stack_ptr_range.start);
+        self.stack_top = self.stack_ptr_range.start;
+        self.frames.clear();
     }
 
-    fn push(&mut self, value: Value) {
+    pub(crate) fn push(&mut self, value: Value) {
         // SAFETY: The stack pointer always points within the range of the stack
         // TODO(safety): What if it points to the end, so the first memory value after the end?
         unsafe { *self.stack_top = value };
@@ -173,7 +1026,12 @@ impl VM {
         };
     }
 
-    fn pop(&mut self) -> Value {
+    pub(crate) fn peek(&self, distance: usize) -> Value {
+        // TODO(safety): What if there are less than `distance + 1` values on the stack?
+        unsafe { *self.stack_top.sub(distance + 1) }
+    }
+
+    pub(crate) fn pop(&mut self) -> Value {
         // TODO(safety): What if we have no values on the stack? This would index out
         unsafe {
             self.stack_top = self.stack_top.sub(1);
@@ -182,4 +1040,88 @@ impl VM {
         // SAFETY: We have checked that the pointer is in range of the stack
         unsafe { *self.stack_top }
     }
+
+    pub(crate) fn copy_string(&mut self, chars: &str) -> *mut ObjString {
+        let hash = hash_string(chars);
+        if let Some(interned) = self.strings.find_string(chars, hash) {
+            return interned;
+        }
+
+        self.allocate_string(chars.to_owned(), hash)
+    }
+
+    /// Like `copy_string`, but takes ownership of an already allocated `String`
+    pub(crate) fn take_string(&mut self, chars: String) -> *mut ObjString {
+        let hash = hash_string(&chars);
+        if let Some(interned) = self.strings.find_string(&chars, hash) {
+            return interned;
+        }
+
+        self.allocate_string(chars, hash)
+    }
+
+    pub(crate) fn new_function(&mut self) -> *mut ObjFunction {
+        self.allocate_object(ObjFunction::new())
+    }
+
+    fn new_closure(&mut self, function: *mut ObjFunction) -> *mut ObjClosure {
+        self.allocate_object(ObjClosure::new(function))
+    }
+
+    pub(crate) fn new_list(&mut self, items: Vec<Value>) -> *mut ObjList {
+        self.allocate_object(ObjList::new(items))
+    }
+
+    fn new_class(&mut self, name: *mut ObjString) -> *mut ObjClass {
+        self.allocate_object(ObjClass::new(name))
+    }
+
+    fn new_bound_method(
+        &mut self,
+        receiver: Value,
+        method: *mut ObjClosure,
+    ) -> *mut ObjBoundMethod {
+        self.allocate_object(ObjBoundMethod::new(receiver, method))
+    }
+
+    fn new_instance(&mut self, class: *mut ObjClass) -> *mut ObjInstance {
+        self.allocate_object(ObjInstance::new(class))
+    }
+
+    fn allocate_string(&mut self, chars: String, hash: u32) -> *mut ObjString {
+        let string = self.allocate_object(ObjString::new(chars, hash));
+        self.strings.set(string, Value::Nil);
+        string
+    }
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        VM::new()
+    }
+}
+
+impl Drop for VM {
+    fn drop(&mut self) {
+        self.free_objects();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runtime_errors_close_upvalues() {
+        let mut vm = VM::new();
+        let result = vm.interpret(
+            "test.lox",
+            "var g; fun f() { var x = 42; fun h() { return x; } g = h; return nil + 1; } f();",
+        );
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+
+        // The captured local must have been moved off the stack, which is reused by the next script
+        let result = vm.interpret("test.lox", "var y = 0; if (g() != 42) nil + 1;");
+        assert!(matches!(result, InterpretResult::Ok));
+    }
 }